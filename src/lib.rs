@@ -1,30 +1,723 @@
 use borsh::{BorshDeserialize, BorshSerialize};
+use bytemuck::{Pod, Zeroable};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    clock::DEFAULT_MS_PER_SLOT,
     entrypoint,
     entrypoint::ProgramResult,
-    msg,
-    program::invoke,
+    keccak, msg,
+    program::{invoke, invoke_signed, set_return_data},
     program_error::ProgramError,
+    program_pack::Pack,
+    pubkey,
     pubkey::Pubkey,
-    sysvar::{clock::Clock, Sysvar},
+    system_instruction,
+    sysvar::{clock::Clock, epoch_schedule::EpochSchedule, rent::Rent, Sysvar},
 };
-use spl_token::instruction::transfer;
+use spl_token::{
+    instruction::{burn, initialize_account3, mint_to, set_authority, transfer, AuthorityType},
+    state::{Account as TokenAccount, Mint},
+};
+use std::cell::RefMut;
 
-// Define the structure for the staking account
-#[derive(BorshSerialize, BorshDeserialize, Debug)]
+// Maximum number of extra reward streams a pool can carry
+pub const MAX_REWARD_STREAMS: usize = 4;
+
+// Fixed-point scale for the per-token reward accumulators
+pub const REWARD_PRECISION: u128 = 1_000_000_000_000;
+
+// Permission bits a position owner can grant to a delegate
+pub const DELEGATE_CLAIM: u8 = 1 << 0;
+pub const DELEGATE_COMPOUND: u8 = 1 << 1;
+pub const DELEGATE_RESTAKE: u8 = 1 << 2;
+
+// Metaplex token metadata program, used to verify boost NFT collections
+pub const TOKEN_METADATA_PROGRAM_ID: Pubkey =
+    pubkey!("metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s");
+
+// Number of balance checkpoints kept per position and per pool
+pub const MAX_CHECKPOINTS: usize = 16;
+
+// Account discriminator spl-governance expects on voter weight add-in records
+pub const VOTER_WEIGHT_RECORD_DISCRIMINATOR: [u8; 8] = [46, 249, 155, 75, 153, 248, 116, 9];
+
+// Voter weight records live at PDAs of (realm, governing token mint, owner), sized for
+// the largest record spl-governance can read
+pub const VOTER_WEIGHT_RECORD_SEED: &[u8] = b"voter-weight-record";
+pub const VOTER_WEIGHT_RECORD_LEN: usize = 164;
+
+// Units a pool can accrue rewards in. Every stored time, duration and rate
+// follows the pool's unit.
+pub const ACCRUAL_UNIX_TIME: u8 = 0;
+pub const ACCRUAL_SLOT: u8 = 1;
+pub const ACCRUAL_EPOCH: u8 = 2;
+
+// Fixed-point scale for per-unit rates derived from an APR and for the pool's
+// staked-token reward accumulator
+pub const RATE_PRECISION: u128 = 1_000_000_000_000_000_000;
+
+// Staked-token rate, per 1000 tokens per unit, of pools with neither a schedule nor an APR
+pub const LEGACY_REWARD_RATE: u64 = 10;
+
+// Length of the year an APR is quoted over
+pub const MS_PER_YEAR: u128 = 365 * 24 * 60 * 60 * 1000;
+
+// Seed for the PDA that owns the pool's reward vaults
+pub const POOL_AUTHORITY_SEED: &[u8] = b"authority";
+
+// Seeds for pools created through CreatePool and their principal vaults
+pub const POOL_SEED: &[u8] = b"pool";
+pub const POOL_VAULT_SEED: &[u8] = b"vault";
+
+// Seeds for a user's position counter and the positions opened through it
+pub const USER_POSITIONS_SEED: &[u8] = b"positions";
+pub const POSITION_SEED: &[u8] = b"position";
+
+// Seed for the record of which position a boost NFT is applied to
+pub const BOOST_RECORD_SEED: &[u8] = b"boost";
+
+// Define the structure for the staking account. Fixed #[repr(C)] layout read and
+// written with bytemuck casts; fields are ordered so there is no implicit padding.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
 pub struct StakingAccount {
+    // Always StakingAccount::DISCRIMINATOR, so no other account can be read as a position
+    pub discriminator: [u8; 8],
     pub user: Pubkey,
+    // Pool the position was opened in, it can't be used with any other
+    pub pool: Pubkey,
     pub amount_staked: u64,
     pub stake_start_time: u64,
+    // Per-stream accumulator snapshots and unclaimed balances
+    pub reward_per_token_paid: [PodU128; MAX_REWARD_STREAMS],
+    pub rewards_owed: [u64; MAX_REWARD_STREAMS],
+    // Operator allowed to manage the position, limited by DELEGATE_* bits
+    pub delegate: Pubkey,
+    // When set, whoever holds this supply-1 token owns the position
+    pub position_mint: Pubkey,
+    // Earns a share of this position's rewards, default when there is none
+    pub referrer: Pubkey,
+    // Reward multiplier from a held collection NFT, zero when not boosted
+    pub boost_start_time: u64,
+    pub boost_mint: Pubkey,
+    pub boost_token_account: Pubkey,
+    pub checkpoints: CheckpointHistory,
+    // Minimum duration for this position on top of the pool's `min_stake_duration`,
+    // counted from `lock_start`, when the position was last funded from empty. Claims,
+    // compounding and top-ups leave it alone.
+    pub lock_duration: u64,
+    pub lock_start: u64,
+    // Snapshot of the pool's staked-token accumulator and rewards settled since the last claim
+    pub staked_reward_per_token_paid: PodU128,
+    pub staked_rewards_owed: u64,
+    // Receipt tokens minted for this position's deposits, burned in full on withdrawal
+    pub receipt_amount: u64,
+    pub boost_bps: u16,
+    pub delegate_permissions: u8,
+    pub is_initialized: PodBool,
+    pub _padding: [u8; 4],
+}
+
+impl StakingAccount {
+    pub fn new(user: Pubkey, pool: Pubkey, clock: u64) -> Self {
+        StakingAccount {
+            discriminator: Self::DISCRIMINATOR,
+            user,
+            pool,
+            amount_staked: 0,
+            stake_start_time: clock,
+            is_initialized: true.into(),
+            reward_per_token_paid: [PodU128::default(); MAX_REWARD_STREAMS],
+            rewards_owed: [0; MAX_REWARD_STREAMS],
+            delegate: Pubkey::default(),
+            delegate_permissions: 0,
+            position_mint: Pubkey::default(),
+            referrer: Pubkey::default(),
+            boost_bps: 0,
+            boost_start_time: 0,
+            boost_mint: Pubkey::default(),
+            boost_token_account: Pubkey::default(),
+            checkpoints: CheckpointHistory::default(),
+            lock_duration: 0,
+            lock_start: clock,
+            staked_reward_per_token_paid: PodU128::default(),
+            staked_rewards_owed: 0,
+            receipt_amount: 0,
+            _padding: [0; 4],
+        }
+    }
+
+    pub const LEN: usize = std::mem::size_of::<Self>();
+    pub const DISCRIMINATOR: [u8; 8] = *b"position";
+
+    // View the position in place inside account data, without copying it out. The data
+    // must be exactly one tagged position.
+    pub fn load(data: &[u8]) -> Result<&Self, ProgramError> {
+        bytemuck::try_from_bytes::<Self>(data)
+            .ok()
+            .filter(|position| position.discriminator == Self::DISCRIMINATOR)
+            .ok_or(ProgramError::InvalidAccountData)
+    }
+
+    pub fn load_mut(data: &mut [u8]) -> Result<&mut Self, ProgramError> {
+        bytemuck::try_from_bytes_mut::<Self>(data)
+            .ok()
+            .filter(|position| position.discriminator == Self::DISCRIMINATOR)
+            .ok_or(ProgramError::InvalidAccountData)
+    }
+
+    // View a blank account of exactly the position's size so it can be written in place
+    pub fn load_uninitialized(data: &mut [u8]) -> Result<&mut Self, ProgramError> {
+        let position = bytemuck::try_from_bytes_mut::<Self>(data)
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+        if position.discriminator != [0; 8] {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+        Ok(position)
+    }
+}
+
+// Bool stored as a single byte so it can live in a Pod account layout
+#[repr(transparent)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+pub struct PodBool(u8);
+
+impl PodBool {
+    pub fn get(self) -> bool {
+        self.0 != 0
+    }
+}
+
+impl From<bool> for PodBool {
+    fn from(value: bool) -> Self {
+        PodBool(value as u8)
+    }
+}
+
+// Little-endian u128 with byte alignment, u128 alignment differs between host and SBF
+#[repr(transparent)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+pub struct PodU128([u8; 16]);
+
+impl PodU128 {
+    pub fn get(self) -> u128 {
+        u128::from_le_bytes(self.0)
+    }
+}
+
+impl From<u128> for PodU128 {
+    fn from(value: u128) -> Self {
+        PodU128(value.to_le_bytes())
+    }
+}
+
+// Maximum number of segments in a pool's emission schedule
+pub const MAX_EMISSION_SEGMENTS: usize = 8;
+
+// A reward rate that applies from `start_time` until the next segment starts
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+pub struct EmissionSegment {
+    pub start_time: u64,
     pub reward_rate: u64,
+}
+
+// Claimed rewards that unlock linearly until `end_time`
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct VestingAccount {
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub locked_amount: u64,
+    pub unlocked_amount: u64,
+    pub last_update_time: u64,
+    pub end_time: u64,
+    pub is_initialized: bool,
+}
+
+impl VestingAccount {
+    // Move the share of the locked amount that has vested since the last update
+    pub fn unlock(&mut self, clock: u64) {
+        if clock <= self.last_update_time {
+            return;
+        }
+        let vested = if clock >= self.end_time {
+            self.locked_amount
+        } else {
+            let elapsed = (clock - self.last_update_time) as u128;
+            let remaining = (self.end_time - self.last_update_time) as u128;
+            (self.locked_amount as u128 * elapsed / remaining) as u64
+        };
+        self.locked_amount -= vested;
+        self.unlocked_amount += vested;
+        self.last_update_time = clock;
+    }
+}
+
+// Counts the positions a user has opened in a pool, each at PDA (pool, owner, index),
+// and the total the user has staked across every position in the pool
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct UserPositions {
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub position_count: u64,
+    pub amount_staked: u64,
+    // Part of `amount_staked` in positions held as tokens, which carries no voter weight
+    pub tokenized_staked: u64,
+    // Amount-weighted start of the rest, which the voter weight lockup bonus ages from
+    pub vote_start_time: u64,
+    pub is_initialized: bool,
+}
+
+impl UserPositions {
+    // Stake that counts towards the owner's voter weight
+    pub fn voting_staked(&self) -> u64 {
+        self.amount_staked.saturating_sub(self.tokenized_staked)
+    }
+
+    // Add `amount` staked from `active_from` into one of the owner's positions. A new
+    // deposit moves the voting start only by its share, so older stake keeps its age.
+    pub fn deposit(
+        &mut self,
+        staking_data: &StakingAccount,
+        amount: u64,
+        active_from: u64,
+    ) -> ProgramResult {
+        let voting = self.voting_staked() as u128;
+        self.amount_staked = self
+            .amount_staked
+            .checked_add(amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        if staking_data.position_mint != Pubkey::default() {
+            self.tokenized_staked = self
+                .tokenized_staked
+                .checked_add(amount)
+                .ok_or(ProgramError::ArithmeticOverflow)?;
+            return Ok(());
+        }
+
+        // Rounds up so the blend never credits time before the deposit was active
+        let weighted = voting * self.vote_start_time as u128 + amount as u128 * active_from as u128;
+        let blended = weighted.div_ceil(voting + amount as u128) as u64;
+        // A clock that went backwards must never rewind the start
+        self.vote_start_time = blended.max(self.vote_start_time);
+        Ok(())
+    }
+
+    // Take `amount` withdrawn from one of the owner's positions off the totals
+    pub fn withdraw(&mut self, staking_data: &StakingAccount, amount: u64) {
+        self.amount_staked = self.amount_staked.saturating_sub(amount);
+        if staking_data.position_mint != Pubkey::default() {
+            self.tokenized_staked = self.tokenized_staked.saturating_sub(amount);
+        }
+        if self.voting_staked() == 0 {
+            self.vote_start_time = 0;
+        }
+    }
+}
+
+// A staked balance and the unix timestamp it took effect, whatever the pool's accrual unit
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+pub struct Checkpoint {
+    pub timestamp: u64,
+    pub balance: u64,
+}
+
+// Ring buffer of the most recent balance checkpoints, oldest entries are overwritten
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+pub struct CheckpointHistory {
+    pub entries: [Checkpoint; MAX_CHECKPOINTS],
+    pub head: u8,
+    pub len: u8,
+    pub _padding: [u8; 6],
+}
+
+impl CheckpointHistory {
+    pub fn push(&mut self, timestamp: u64, balance: u64) {
+        // Several changes in the same second collapse into one checkpoint, as do
+        // changes stamped before the latest one if the clock went backwards
+        if self.len > 0 {
+            let last = (self.head as usize + MAX_CHECKPOINTS - 1) % MAX_CHECKPOINTS;
+            if self.entries[last].timestamp >= timestamp {
+                self.entries[last].balance = balance;
+                return;
+            }
+        }
+        self.entries[self.head as usize] = Checkpoint { timestamp, balance };
+        self.head = ((self.head as usize + 1) % MAX_CHECKPOINTS) as u8;
+        self.len = (self.len as usize + 1).min(MAX_CHECKPOINTS) as u8;
+    }
+
+    // Balance in effect at `timestamp`, or None if it predates the retained history
+    pub fn balance_at(&self, timestamp: u64) -> Option<u64> {
+        let oldest = (self.head as usize + MAX_CHECKPOINTS - self.len as usize) % MAX_CHECKPOINTS;
+        for i in (0..self.len as usize).rev() {
+            let entry = self.entries[(oldest + i) % MAX_CHECKPOINTS];
+            if entry.timestamp <= timestamp {
+                return Some(entry.balance);
+            }
+        }
+        // Before the first checkpoint nothing was staked, unless older entries were overwritten
+        if (self.len as usize) < MAX_CHECKPOINTS {
+            Some(0)
+        } else {
+            None
+        }
+    }
+}
+
+// Aggregate counters kept alongside the pool's `total_staked`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+pub struct PoolStats {
+    pub active_stakers: u64,
+    // Staked-token rewards paid, vested or compounded
+    pub total_rewards_paid: u64,
+    pub total_deposited: u64,
+    pub total_withdrawn: u64,
+    pub total_reward_fees: u64,
+    pub total_deposit_fees: u64,
+    pub total_withdraw_fees: u64,
+}
+
+// Snapshot returned by GetPendingRewards through return data
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq, Eq)]
+pub struct PendingRewards {
+    pub timestamp: u64,
+    pub amount_staked: u64,
+    // Staked-token rewards net of the protocol fee, zero until the minimum duration is reached
+    pub rewards: u64,
+    pub min_duration_reached: bool,
+    pub stream_rewards: [u64; MAX_REWARD_STREAMS],
+    // Rate the position currently accrues staked-token rewards at, before boosts
+    pub apr_bps: u64,
+}
+
+// Governance action a voter weight is restricted to, mirrors spl-governance-addin-api
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoterWeightAction {
+    CastVote,
+    CommentProposal,
+    CreateGovernance,
+    CreateProposal,
+    SignOffProposal,
+}
+
+// Voter weight record laid out as spl-governance reads it from an add-in
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct VoterWeightRecord {
+    pub account_discriminator: [u8; 8],
+    pub realm: Pubkey,
+    pub governing_token_mint: Pubkey,
+    pub governing_token_owner: Pubkey,
+    pub voter_weight: u64,
+    pub voter_weight_expiry: Option<u64>,
+    pub weight_action: Option<VoterWeightAction>,
+    pub weight_action_target: Option<Pubkey>,
+    pub reserved: [u8; 8],
+}
+
+// Referral rewards a referrer has accrued in a pool
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct ReferralAccount {
+    pub referrer: Pubkey,
+    pub pool: Pubkey,
+    pub claimable: u64,
+    pub is_initialized: bool,
+}
+
+// The one position in a pool a boost NFT currently boosts, at PDA (pool, NFT mint)
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct BoostRecord {
+    pub pool: Pubkey,
+    pub mint: Pubkey,
+    pub position: Pubkey,
     pub is_initialized: bool,
 }
 
+// The leading fields of a Metaplex metadata account, up to its collection
+#[derive(BorshDeserialize)]
+struct MetadataPrefix {
+    _key: u8,
+    _update_authority: Pubkey,
+    mint: Pubkey,
+    _name: String,
+    _symbol: String,
+    _uri: String,
+    _seller_fee_basis_points: u16,
+    _creators: Option<Vec<MetadataCreator>>,
+    _primary_sale_happened: bool,
+    _is_mutable: bool,
+    _edition_nonce: Option<u8>,
+    _token_standard: Option<u8>,
+    collection: Option<MetadataCollection>,
+}
+
+#[derive(BorshDeserialize)]
+struct MetadataCreator {
+    _address: Pubkey,
+    _verified: bool,
+    _share: u8,
+}
+
+#[derive(BorshDeserialize)]
+struct MetadataCollection {
+    verified: bool,
+    key: Pubkey,
+}
+
+// An additional reward token paid out pro rata to everyone staked in the pool
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+pub struct RewardStream {
+    pub mint: Pubkey,
+    pub vault: Pubkey,
+    // Tokens emitted per second across the whole pool
+    pub reward_rate: u64,
+    pub reward_per_token_stored: PodU128,
+    // Epoch pools only: the accumulator at the end of the current epoch, priced against
+    // the stake active when the epoch is first touched
+    pub reward_per_token_next: PodU128,
+    pub last_update_time: u64,
+}
+
+// Define the structure for the pool-wide configuration. Like StakingAccount this is a
+// fixed #[repr(C)] layout with wide fields first and the narrow ones packed at the end.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, Pod, Zeroable)]
+pub struct StakingPool {
+    // Always StakingPool::DISCRIMINATOR, so no other account can be read as a pool
+    pub discriminator: [u8; 8],
+    pub admin: Pubkey,
+    // When non-zero, the rate of the last segment halves every `halving_period` seconds
+    pub halving_period: u64,
+    pub emission_schedule: [EmissionSegment; MAX_EMISSION_SEGMENTS],
+    // Deposit caps, zero means unlimited
+    pub max_total_staked: u64,
+    pub max_per_user: u64,
+    pub total_staked: u64,
+    // Smallest accepted deposit and shortest stake that still earns rewards
+    pub min_stake_amount: u64,
+    pub min_stake_duration: u64,
+    pub reward_streams: [RewardStream; MAX_REWARD_STREAMS],
+    // Liquid receipt token minted by the pool authority, default when disabled
+    pub receipt_mint: Pubkey,
+    // Claimed rewards vest over this many seconds from `vesting_vault`, zero pays them at once
+    pub vesting_period: u64,
+    pub vesting_vault: Pubkey,
+    // Referrers earn `referral_bps` of their referees' rewards, paid from this vault
+    pub referral_vault: Pubkey,
+    // Holders of a verified NFT from this collection earn `boost_multiplier_bps` of base rewards
+    pub boost_collection: Pubkey,
+    // Voter weight grows by up to `vote_lockup_bonus_bps` as a stake ages towards this many seconds
+    pub vote_lockup_saturation: u64,
+    // Realm and governing token mint voter weight records are issued for, default until
+    // the admin pins them
+    pub voter_weight_realm: Pubkey,
+    pub voter_weight_mint: Pubkey,
+    pub checkpoints: CheckpointHistory,
+    pub stats: PoolStats,
+    // Receives the reward, deposit and withdraw fees below
+    pub treasury: Pubkey,
+    // Merkle root of allowed (owner, max allocation) leaves; all zeroes leaves the pool open
+    pub allowlist_root: [u8; 32],
+    // Mint and principal vault deposits must land in. Legacy pools initialized before the
+    // vault was recorded leave these zeroed and only allow withdrawals.
+    pub stake_mint: Pubkey,
+    pub vault: Pubkey,
+    pub pool_seed: u64,
+    // Staked-token rewards per token since the pool was created, scaled by RATE_PRECISION.
+    // Epoch pools also keep the value at the end of the current epoch, which is priced
+    // when the epoch is first touched, so rate changes apply from the next boundary.
+    pub staked_reward_per_token: PodU128,
+    pub staked_reward_per_token_next: PodU128,
+    pub staked_reward_last_update: u64,
+    // Annual rate paid on staked tokens when there is no emission schedule; zero keeps
+    // the legacy constant rate
+    pub apr_bps: u32,
+    pub referral_bps: u16,
+    pub boost_multiplier_bps: u16,
+    pub vote_lockup_bonus_bps: u16,
+    // Protocol cut of staked-token rewards
+    pub reward_fee_bps: u16,
+    // Cut of principal taken on stake and unstake
+    pub deposit_fee_bps: u16,
+    pub withdraw_fee_bps: u16,
+    pub is_paused: PodBool,
+    pub is_initialized: PodBool,
+    pub segment_count: u8,
+    pub stream_count: u8,
+    // One of the ACCRUAL_* units
+    pub accrual_mode: u8,
+    pub _padding: [u8; 3],
+}
+
+impl StakingPool {
+    pub const LEN: usize = std::mem::size_of::<Self>();
+    pub const DISCRIMINATOR: [u8; 8] = *b"stk_pool";
+
+    // View the pool in place inside account data, without copying it out. The data
+    // must be exactly one tagged pool.
+    pub fn load(data: &[u8]) -> Result<&Self, ProgramError> {
+        bytemuck::try_from_bytes::<Self>(data)
+            .ok()
+            .filter(|pool| pool.discriminator == Self::DISCRIMINATOR)
+            .ok_or(ProgramError::InvalidAccountData)
+    }
+
+    pub fn load_mut(data: &mut [u8]) -> Result<&mut Self, ProgramError> {
+        bytemuck::try_from_bytes_mut::<Self>(data)
+            .ok()
+            .filter(|pool| pool.discriminator == Self::DISCRIMINATOR)
+            .ok_or(ProgramError::InvalidAccountData)
+    }
+
+    // View a blank account of exactly the pool's size so it can be written in place
+    pub fn load_uninitialized(data: &mut [u8]) -> Result<&mut Self, ProgramError> {
+        let pool = bytemuck::try_from_bytes_mut::<Self>(data)
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+        if pool.discriminator != [0; 8] {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+        Ok(pool)
+    }
+}
+
+// Errors specific to the staking program, surfaced as ProgramError::Custom
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StakingError {
+    PoolPaused,
+    InvalidEmissionSchedule,
+    PoolCapacityExceeded,
+    UserStakeLimitExceeded,
+    BelowMinimumStake,
+    TooManyRewardStreams,
+    InvalidRewardStream,
+    Unauthorized,
+    InvalidReceiptMint,
+    InvalidPositionMint,
+    InvalidVestingAccount,
+    InvalidReferralAccount,
+    InvalidBoostNft,
+    InvalidVoterWeightRecord,
+    CheckpointUnavailable,
+    InvalidTreasury,
+    NotAllowlisted,
+    AllocationExceeded,
+    InvalidVault,
+    InvalidPositionPool,
+}
+
+impl From<StakingError> for ProgramError {
+    fn from(e: StakingError) -> Self {
+        ProgramError::Custom(e as u32)
+    }
+}
+
 // Instructions to be handled by the program
 pub enum StakingInstruction {
-    Stake { amount: u64 },
+    Stake {
+        amount: u64,
+        referrer: Option<Pubkey>,
+    },
     Unstake,
+    InitializePool,
+    SetPaused {
+        paused: bool,
+    },
+    EmergencyWithdraw,
+    SetEmissionSchedule {
+        halving_period: u64,
+        segments: Vec<EmissionSegment>,
+    },
+    SetStakeLimits {
+        max_total_staked: u64,
+        max_per_user: u64,
+    },
+    SetStakeMinimums {
+        min_stake_amount: u64,
+        min_stake_duration: u64,
+    },
+    AddRewardStream {
+        reward_rate: u64,
+    },
+    ClaimRewards,
+    SetRewardStreamRate {
+        index: u8,
+        reward_rate: u64,
+    },
+    SetDelegate {
+        delegate: Pubkey,
+        permissions: u8,
+    },
+    RevokeDelegate,
+    Compound,
+    StakeFor {
+        beneficiary: Pubkey,
+        amount: u64,
+    },
+    SetReceiptMint,
+    InitializeStake {
+        nft: bool,
+    },
+    SetVesting {
+        vesting_period: u64,
+    },
+    ReleaseVested,
+    SetReferralConfig {
+        referral_bps: u16,
+    },
+    ClaimReferralRewards,
+    SetBoostConfig {
+        boost_multiplier_bps: u16,
+    },
+    ApplyBoost,
+    UpdateVoterWeightRecord {
+        realm: Pubkey,
+        governing_token_mint: Pubkey,
+    },
+    SetVoterWeightConfig {
+        vote_lockup_bonus_bps: u16,
+        vote_lockup_saturation: u64,
+    },
+    // `timestamp` is a unix timestamp in seconds on every pool
+    GetStakeAt {
+        timestamp: u64,
+    },
+    GetPendingRewards,
+    SetRewardFee {
+        reward_fee_bps: u16,
+    },
+    SetTransferFees {
+        deposit_fee_bps: u16,
+        withdraw_fee_bps: u16,
+    },
+    SetAllowlistRoot {
+        allowlist_root: [u8; 32],
+    },
+    StakeWithProof {
+        amount: u64,
+        // Zero means the leaf carries no allocation cap
+        max_allocation: u64,
+        proof: Vec<[u8; 32]>,
+    },
+    SetAccrualMode {
+        accrual_mode: u8,
+    },
+    SetApr {
+        apr_bps: u32,
+    },
+    CreatePool {
+        seed: u64,
+    },
+    OpenPosition {
+        lock_duration: u64,
+    },
+    SetVoterWeightRealm {
+        realm: Pubkey,
+        governing_token_mint: Pubkey,
+    },
 }
 
 impl StakingInstruction {
@@ -35,9 +728,196 @@ impl StakingInstruction {
         Ok(match tag {
             0 => {
                 let amount = Self::unpack_u64(rest)?;
-                Self::Stake { amount }
+                // An optional referrer pubkey may follow the amount
+                let referrer = match rest.get(8..) {
+                    Some(referrer) if !referrer.is_empty() => Some(Self::unpack_pubkey(referrer)?),
+                    _ => None,
+                };
+                Self::Stake { amount, referrer }
             }
             1 => Self::Unstake,
+            2 => Self::InitializePool,
+            3 => {
+                let paused = Self::unpack_bool(rest)?;
+                Self::SetPaused { paused }
+            }
+            4 => Self::EmergencyWithdraw,
+            5 => {
+                let halving_period = Self::unpack_u64(rest)?;
+                let (count, mut rest) = rest
+                    .get(8..)
+                    .and_then(|rest| rest.split_first())
+                    .ok_or(ProgramError::InvalidInstructionData)?;
+                let mut segments = Vec::with_capacity(*count as usize);
+                for _ in 0..*count {
+                    let start_time = Self::unpack_u64(rest)?;
+                    let reward_rate = Self::unpack_u64(&rest[8..])?;
+                    segments.push(EmissionSegment {
+                        start_time,
+                        reward_rate,
+                    });
+                    rest = &rest[16..];
+                }
+                Self::SetEmissionSchedule {
+                    halving_period,
+                    segments,
+                }
+            }
+            6 => {
+                let max_total_staked = Self::unpack_u64(rest)?;
+                let max_per_user = Self::unpack_u64(rest.get(8..).unwrap_or_default())?;
+                Self::SetStakeLimits {
+                    max_total_staked,
+                    max_per_user,
+                }
+            }
+            7 => {
+                let min_stake_amount = Self::unpack_u64(rest)?;
+                let min_stake_duration = Self::unpack_u64(rest.get(8..).unwrap_or_default())?;
+                Self::SetStakeMinimums {
+                    min_stake_amount,
+                    min_stake_duration,
+                }
+            }
+            8 => {
+                let reward_rate = Self::unpack_u64(rest)?;
+                Self::AddRewardStream { reward_rate }
+            }
+            9 => Self::ClaimRewards,
+            10 => {
+                let (index, rest) = rest
+                    .split_first()
+                    .ok_or(ProgramError::InvalidInstructionData)?;
+                let reward_rate = Self::unpack_u64(rest)?;
+                Self::SetRewardStreamRate {
+                    index: *index,
+                    reward_rate,
+                }
+            }
+            11 => {
+                let delegate = Self::unpack_pubkey(rest)?;
+                let permissions = *rest.get(32).ok_or(ProgramError::InvalidInstructionData)?;
+                Self::SetDelegate {
+                    delegate,
+                    permissions,
+                }
+            }
+            12 => Self::RevokeDelegate,
+            13 => Self::Compound,
+            14 => {
+                let beneficiary = Self::unpack_pubkey(rest)?;
+                let amount = Self::unpack_u64(rest.get(32..).unwrap_or_default())?;
+                Self::StakeFor {
+                    beneficiary,
+                    amount,
+                }
+            }
+            15 => Self::SetReceiptMint,
+            16 => {
+                let nft = Self::unpack_bool(rest)?;
+                Self::InitializeStake { nft }
+            }
+            17 => {
+                let vesting_period = Self::unpack_u64(rest)?;
+                Self::SetVesting { vesting_period }
+            }
+            18 => Self::ReleaseVested,
+            19 => {
+                let referral_bps = Self::unpack_u16(rest)?;
+                Self::SetReferralConfig { referral_bps }
+            }
+            20 => Self::ClaimReferralRewards,
+            21 => {
+                let boost_multiplier_bps = Self::unpack_u16(rest)?;
+                Self::SetBoostConfig {
+                    boost_multiplier_bps,
+                }
+            }
+            22 => Self::ApplyBoost,
+            23 => {
+                let realm = Self::unpack_pubkey(rest)?;
+                let governing_token_mint = Self::unpack_pubkey(rest.get(32..).unwrap_or_default())?;
+                Self::UpdateVoterWeightRecord {
+                    realm,
+                    governing_token_mint,
+                }
+            }
+            24 => {
+                let vote_lockup_bonus_bps = Self::unpack_u16(rest)?;
+                let vote_lockup_saturation = Self::unpack_u64(rest.get(2..).unwrap_or_default())?;
+                Self::SetVoterWeightConfig {
+                    vote_lockup_bonus_bps,
+                    vote_lockup_saturation,
+                }
+            }
+            25 => {
+                let timestamp = Self::unpack_u64(rest)?;
+                Self::GetStakeAt { timestamp }
+            }
+            26 => Self::GetPendingRewards,
+            27 => {
+                let reward_fee_bps = Self::unpack_u16(rest)?;
+                Self::SetRewardFee { reward_fee_bps }
+            }
+            28 => {
+                let deposit_fee_bps = Self::unpack_u16(rest)?;
+                let withdraw_fee_bps = Self::unpack_u16(rest.get(2..).unwrap_or_default())?;
+                Self::SetTransferFees {
+                    deposit_fee_bps,
+                    withdraw_fee_bps,
+                }
+            }
+            29 => {
+                let allowlist_root = Self::unpack_pubkey(rest)?.to_bytes();
+                Self::SetAllowlistRoot { allowlist_root }
+            }
+            30 => {
+                let amount = Self::unpack_u64(rest)?;
+                let max_allocation = Self::unpack_u64(rest.get(8..).unwrap_or_default())?;
+                let (count, nodes) = rest
+                    .get(16..)
+                    .and_then(|rest| rest.split_first())
+                    .ok_or(ProgramError::InvalidInstructionData)?;
+                let proof = (0..*count as usize)
+                    .map(|i| {
+                        Self::unpack_pubkey(nodes.get(i * 32..).unwrap_or_default())
+                            .map(|node| node.to_bytes())
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Self::StakeWithProof {
+                    amount,
+                    max_allocation,
+                    proof,
+                }
+            }
+            31 => {
+                let accrual_mode = *rest.first().ok_or(ProgramError::InvalidInstructionData)?;
+                Self::SetAccrualMode { accrual_mode }
+            }
+            32 => {
+                let apr_bps = rest
+                    .get(..4)
+                    .and_then(|slice| slice.try_into().ok())
+                    .map(u32::from_le_bytes)
+                    .ok_or(ProgramError::InvalidInstructionData)?;
+                Self::SetApr { apr_bps }
+            }
+            33 => {
+                let seed = Self::unpack_u64(rest)?;
+                Self::CreatePool { seed }
+            }
+            34 => {
+                let lock_duration = Self::unpack_u64(rest)?;
+                Self::OpenPosition { lock_duration }
+            }
+            35 => {
+                let realm = Self::unpack_pubkey(rest)?;
+                let governing_token_mint = Self::unpack_pubkey(rest.get(32..).unwrap_or_default())?;
+                Self::SetVoterWeightRealm {
+                    realm,
+                    governing_token_mint,
+                }
+            }
             _ => return Err(ProgramError::InvalidInstructionData),
         })
     }
@@ -50,154 +930,3776 @@ impl StakingInstruction {
             .ok_or(ProgramError::InvalidInstructionData)?;
         Ok(amount)
     }
+
+    fn unpack_u16(input: &[u8]) -> Result<u16, ProgramError> {
+        let value = input
+            .get(..2)
+            .and_then(|slice| slice.try_into().ok())
+            .map(u16::from_le_bytes)
+            .ok_or(ProgramError::InvalidInstructionData)?;
+        Ok(value)
+    }
+
+    fn unpack_pubkey(input: &[u8]) -> Result<Pubkey, ProgramError> {
+        let key = input
+            .get(..32)
+            .and_then(|slice| slice.try_into().ok())
+            .map(Pubkey::new_from_array)
+            .ok_or(ProgramError::InvalidInstructionData)?;
+        Ok(key)
+    }
+
+    fn unpack_bool(input: &[u8]) -> Result<bool, ProgramError> {
+        match input.first() {
+            Some(0) => Ok(false),
+            Some(1) => Ok(true),
+            _ => Err(ProgramError::InvalidInstructionData),
+        }
+    }
 }
 
 entrypoint!(process_instruction);
 
-fn process_instruction(
+pub fn process_instruction(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
     instruction_data: &[u8],
 ) -> ProgramResult {
     let instruction = StakingInstruction::unpack(instruction_data)?;
     match instruction {
-        StakingInstruction::Stake { amount } => stake_tokens(program_id, accounts, amount),
+        StakingInstruction::Stake { amount, referrer } => {
+            stake_tokens(program_id, accounts, amount, referrer, None)
+        }
         StakingInstruction::Unstake => unstake_tokens(program_id, accounts),
+        StakingInstruction::InitializePool => initialize_pool(program_id, accounts),
+        StakingInstruction::SetPaused { paused } => set_paused(program_id, accounts, paused),
+        StakingInstruction::EmergencyWithdraw => emergency_withdraw(program_id, accounts),
+        StakingInstruction::SetEmissionSchedule {
+            halving_period,
+            segments,
+        } => set_emission_schedule(program_id, accounts, halving_period, segments),
+        StakingInstruction::SetStakeLimits {
+            max_total_staked,
+            max_per_user,
+        } => set_stake_limits(program_id, accounts, max_total_staked, max_per_user),
+        StakingInstruction::SetStakeMinimums {
+            min_stake_amount,
+            min_stake_duration,
+        } => set_stake_minimums(program_id, accounts, min_stake_amount, min_stake_duration),
+        StakingInstruction::AddRewardStream { reward_rate } => {
+            add_reward_stream(program_id, accounts, reward_rate)
+        }
+        StakingInstruction::ClaimRewards => claim_rewards(program_id, accounts),
+        StakingInstruction::SetRewardStreamRate { index, reward_rate } => {
+            set_reward_stream_rate(program_id, accounts, index, reward_rate)
+        }
+        StakingInstruction::SetDelegate {
+            delegate,
+            permissions,
+        } => set_delegate(program_id, accounts, delegate, permissions),
+        StakingInstruction::RevokeDelegate => revoke_delegate(program_id, accounts),
+        StakingInstruction::Compound => compound_rewards(program_id, accounts),
+        StakingInstruction::StakeFor {
+            beneficiary,
+            amount,
+        } => stake_for(program_id, accounts, beneficiary, amount),
+        StakingInstruction::SetReceiptMint => set_receipt_mint(program_id, accounts),
+        StakingInstruction::InitializeStake { nft } => initialize_stake(program_id, accounts, nft),
+        StakingInstruction::SetVesting { vesting_period } => {
+            set_vesting(program_id, accounts, vesting_period)
+        }
+        StakingInstruction::ReleaseVested => release_vested(program_id, accounts),
+        StakingInstruction::SetReferralConfig { referral_bps } => {
+            set_referral_config(program_id, accounts, referral_bps)
+        }
+        StakingInstruction::ClaimReferralRewards => claim_referral_rewards(program_id, accounts),
+        StakingInstruction::SetBoostConfig {
+            boost_multiplier_bps,
+        } => set_boost_config(program_id, accounts, boost_multiplier_bps),
+        StakingInstruction::ApplyBoost => apply_boost(program_id, accounts),
+        StakingInstruction::UpdateVoterWeightRecord {
+            realm,
+            governing_token_mint,
+        } => update_voter_weight_record(program_id, accounts, realm, governing_token_mint),
+        StakingInstruction::SetVoterWeightConfig {
+            vote_lockup_bonus_bps,
+            vote_lockup_saturation,
+        } => set_voter_weight_config(
+            program_id,
+            accounts,
+            vote_lockup_bonus_bps,
+            vote_lockup_saturation,
+        ),
+        StakingInstruction::GetStakeAt { timestamp } => {
+            get_stake_at(program_id, accounts, timestamp)
+        }
+        StakingInstruction::GetPendingRewards => get_pending_rewards(program_id, accounts),
+        StakingInstruction::SetRewardFee { reward_fee_bps } => {
+            set_reward_fee(program_id, accounts, reward_fee_bps)
+        }
+        StakingInstruction::SetTransferFees {
+            deposit_fee_bps,
+            withdraw_fee_bps,
+        } => set_transfer_fees(program_id, accounts, deposit_fee_bps, withdraw_fee_bps),
+        StakingInstruction::SetAllowlistRoot { allowlist_root } => {
+            set_allowlist_root(program_id, accounts, allowlist_root)
+        }
+        StakingInstruction::StakeWithProof {
+            amount,
+            max_allocation,
+            proof,
+        } => stake_tokens(
+            program_id,
+            accounts,
+            amount,
+            None,
+            Some((max_allocation, proof)),
+        ),
+        StakingInstruction::SetAccrualMode { accrual_mode } => {
+            set_accrual_mode(program_id, accounts, accrual_mode)
+        }
+        StakingInstruction::SetApr { apr_bps } => set_apr(program_id, accounts, apr_bps),
+        StakingInstruction::CreatePool { seed } => create_pool(program_id, accounts, seed),
+        StakingInstruction::OpenPosition { lock_duration } => {
+            open_position(program_id, accounts, lock_duration)
+        }
+        StakingInstruction::SetVoterWeightRealm {
+            realm,
+            governing_token_mint,
+        } => set_voter_weight_realm(program_id, accounts, realm, governing_token_mint),
     }
 }
 
-fn stake_tokens(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
-    let accounts_iter = &mut accounts.iter();
-    let staking_account = next_account_info(accounts_iter)?;
-    let user_account = next_account_info(accounts_iter)?;
-    let token_account = next_account_info(accounts_iter)?;
-    let token_program = next_account_info(accounts_iter)?;
-    let clock = Clock::get()?.unix_timestamp as u64;
-
-    // Ensure that the staking account belongs to this program
-    if staking_account.owner != program_id {
-        msg!("Staking account does not have the correct program ID");
+// Borrow the pool configuration in place and make sure it belongs to this program.
+// Changes made through the borrow land directly in the account data.
+fn load_pool<'b>(
+    program_id: &Pubkey,
+    pool_account: &'b AccountInfo,
+) -> Result<RefMut<'b, StakingPool>, ProgramError> {
+    if pool_account.owner != program_id {
+        msg!("Pool account does not have the correct program ID");
         return Err(ProgramError::IncorrectProgramId);
     }
 
-    // Check if the account data is empty or not initialized
-    let mut staking_data: StakingAccount;
-    if staking_account.data_is_empty() {
-        // Initialize staking account if it's empty
-        msg!("Initializing staking account");
-        staking_data = StakingAccount {
-            user: *user_account.key,
-            amount_staked: 0,
-            stake_start_time: clock,
-            reward_rate: 10,
-            is_initialized: true,
-        };
-    } else {
-        // Attempt to deserialize the existing data
-        staking_data =
-            StakingAccount::try_from_slice(&staking_account.data.borrow()).map_err(|_| {
-                msg!("Failed to deserialize staking account data");
-                ProgramError::InvalidAccountData
-            })?;
-    }
+    let pool_data = RefMut::filter_map(pool_account.try_borrow_mut_data()?, |data| {
+        StakingPool::load_mut(data).ok()
+    })
+    .map_err(|_| {
+        msg!("Failed to deserialize pool account data");
+        ProgramError::InvalidAccountData
+    })?;
 
-    if !staking_data.is_initialized {
-        msg!("Staking account is not initialized");
+    if !pool_data.is_initialized.get() {
+        msg!("Pool account is not initialized");
         return Err(ProgramError::UninitializedAccount);
     }
 
-    if amount == 0 {
-        msg!("Staking amount cannot be zero");
-        return Err(ProgramError::InvalidArgument);
+    Ok(pool_data)
+}
+
+// Borrow a position in place for the rest of the instruction
+fn load_position<'b>(
+    staking_account: &'b AccountInfo,
+) -> Result<RefMut<'b, StakingAccount>, ProgramError> {
+    RefMut::filter_map(staking_account.try_borrow_mut_data()?, |data| {
+        StakingAccount::load_mut(data).ok()
+    })
+    .map_err(|_| ProgramError::InvalidAccountData)
+}
+
+// Make sure the signer is the admin recorded on the pool
+fn check_admin(pool_data: &StakingPool, admin_account: &AccountInfo) -> ProgramResult {
+    if !admin_account.is_signer {
+        msg!("Admin signature is required");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if pool_data.admin != *admin_account.key {
+        msg!("Signer is not the pool admin");
+        return Err(ProgramError::InvalidAccountData);
     }
+    Ok(())
+}
 
-    // Transfer tokens from user to staking account
-    let transfer_ix = transfer(
-        token_program.key,
-        user_account.key,
-        token_account.key,
-        staking_account.key, // staking account authority
-        &[],
-        amount,
-    )?;
-    invoke(
-        &transfer_ix,
-        &[
-            user_account.clone(),
-            token_account.clone(),
-            token_program.clone(),
-        ],
-    )?;
+// Positions only ever move funds of the pool they were opened in
+fn check_position_pool(staking_data: &StakingAccount, pool_account: &AccountInfo) -> ProgramResult {
+    if staking_data.pool != *pool_account.key {
+        msg!("Staking account belongs to pool {}", staking_data.pool);
+        return Err(StakingError::InvalidPositionPool.into());
+    }
+    Ok(())
+}
 
-    // Update staking details
-    staking_data.amount_staked += amount;
-    staking_data.stake_start_time = clock;
+// Make sure the signer is the position owner or a delegate holding `permission`
+fn check_position_authority(
+    staking_data: &StakingAccount,
+    signer_account: &AccountInfo,
+    permission: u8,
+) -> ProgramResult {
+    if !signer_account.is_signer {
+        msg!("Position owner or delegate signature is required");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if staking_data.user == *signer_account.key {
+        return Ok(());
+    }
+    if staking_data.delegate == *signer_account.key
+        && staking_data.delegate_permissions & permission == permission
+    {
+        return Ok(());
+    }
+    msg!("Signer is not allowed to operate this position");
+    Err(StakingError::Unauthorized.into())
+}
 
-    // Serialize and save the staking account data
-    staking_data
-        .serialize(&mut &mut staking_account.data.borrow_mut()[..])
-        .map_err(|_| {
-            msg!("Failed to serialize staking account data");
-            ProgramError::InvalidAccountData
-        })?;
+// Prove the signer owns the position, either directly or by holding its position token
+fn check_position_owner(
+    staking_data: &StakingAccount,
+    signer_account: &AccountInfo,
+    accounts_iter: &mut std::slice::Iter<AccountInfo>,
+) -> ProgramResult {
+    if !signer_account.is_signer {
+        msg!("Position owner signature is required");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
 
-    msg!("Staked {} tokens successfully!", amount);
+    if staking_data.position_mint == Pubkey::default() {
+        if staking_data.user != *signer_account.key {
+            msg!("Only the staking account owner can operate this position");
+            return Err(StakingError::Unauthorized.into());
+        }
+        return Ok(());
+    }
+
+    let holder_account = next_account_info(accounts_iter)?;
+    if holder_account.owner != &spl_token::id() {
+        msg!("Position token account is not a token account");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let holder_data = TokenAccount::unpack(&holder_account.data.borrow())?;
+    if holder_data.mint != staking_data.position_mint
+        || holder_data.amount != 1
+        || holder_data.owner != *signer_account.key
+    {
+        msg!("Signer does not hold the position token");
+        return Err(StakingError::Unauthorized.into());
+    }
     Ok(())
 }
 
-fn unstake_tokens(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+// Authorize the owner, the position token holder, or a delegate with `permission`.
+// Returns true when the signer is acting as a delegate.
+fn check_position_signer(
+    staking_data: &StakingAccount,
+    signer_account: &AccountInfo,
+    accounts_iter: &mut std::slice::Iter<AccountInfo>,
+    permission: u8,
+) -> Result<bool, ProgramError> {
+    if staking_data.position_mint != Pubkey::default() {
+        check_position_owner(staking_data, signer_account, accounts_iter)?;
+        return Ok(false);
+    }
+    check_position_authority(staking_data, signer_account, permission)?;
+    Ok(staking_data.user != *signer_account.key)
+}
+
+fn initialize_pool(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
     let accounts_iter = &mut accounts.iter();
-    let staking_account = next_account_info(accounts_iter)?;
-    let user_account = next_account_info(accounts_iter)?;
-    let token_account = next_account_info(accounts_iter)?;
-    let token_program = next_account_info(accounts_iter)?;
-    let clock = Clock::get()?.unix_timestamp as u64;
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
 
-    // Ensure that the staking account belongs to this program
-    if staking_account.owner != program_id {
+    if pool_account.owner != program_id {
+        msg!("Pool account does not have the correct program ID");
         return Err(ProgramError::IncorrectProgramId);
     }
 
-    // Deserialize staking account
-    let mut staking_data: StakingAccount =
-        StakingAccount::try_from_slice(&staking_account.data.borrow())?;
-
-    if !staking_data.is_initialized {
-        return Err(ProgramError::UninitializedAccount);
+    if !admin_account.is_signer {
+        msg!("Admin signature is required");
+        return Err(ProgramError::MissingRequiredSignature);
     }
 
-    let duration_staked = clock - staking_data.stake_start_time;
-    let rewards = staking_data.amount_staked * staking_data.reward_rate * duration_staked / 1000;
-    let total_amount = staking_data.amount_staked + rewards;
-
-    let transfer_ix = transfer(
-        token_program.key,
-        token_account.key,
-        user_account.key,
-        staking_account.key, // staking account authority
-        &[],
-        total_amount,
-    )?;
-    invoke(
-        &transfer_ix,
-        &[
-            token_account.clone(),
-            user_account.clone(),
-            token_program.clone(),
-        ],
-    )?;
+    // Deposits are only credited once they land in this vault, which only the pool
+    // authority can move tokens out of
+    if vault.owner != &spl_token::id() {
+        msg!("Vault is not a token account");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let vault_data = TokenAccount::unpack(&vault.data.borrow())?;
+    let (authority_key, _) = find_pool_authority(program_id, pool_account.key);
+    if vault_data.owner != authority_key
+        || vault_data.delegate.is_some()
+        || vault_data.close_authority.is_some()
+    {
+        msg!("Vault must be owned by the pool authority with no delegate or close authority");
+        return Err(StakingError::InvalidVault.into());
+    }
 
-    // Reset staking account
-    staking_data.amount_staked = 0;
-    staking_data.stake_start_time = 0;
+    let mut pool_ref = pool_account.try_borrow_mut_data()?;
+    let pool_data = StakingPool::load_uninitialized(&mut pool_ref).inspect_err(|err| {
+        if *err == ProgramError::AccountAlreadyInitialized {
+            msg!("Pool account is already initialized");
+        } else {
+            msg!("Pool account must be exactly {} bytes", StakingPool::LEN);
+        }
+    })?;
+    let clock = Clock::get()?;
 
-    // Serialize and save the staking account data
-    staking_data.serialize(&mut &mut staking_account.data.borrow_mut()[..])?;
+    pool_data.discriminator = StakingPool::DISCRIMINATOR;
+    pool_data.admin = *admin_account.key;
+    pool_data.is_paused = false.into();
+    pool_data.is_initialized = true.into();
+    pool_data.halving_period = 0;
+    pool_data.segment_count = 0;
+    pool_data.max_total_staked = 0;
+    pool_data.max_per_user = 0;
+    pool_data.total_staked = 0;
+    pool_data.min_stake_amount = 0;
+    pool_data.min_stake_duration = 0;
+    pool_data.stream_count = 0;
+    pool_data.receipt_mint = Pubkey::default();
+    pool_data.vesting_period = 0;
+    pool_data.vesting_vault = Pubkey::default();
+    pool_data.referral_bps = 0;
+    pool_data.referral_vault = Pubkey::default();
+    pool_data.boost_collection = Pubkey::default();
+    pool_data.boost_multiplier_bps = 0;
+    pool_data.vote_lockup_bonus_bps = 0;
+    pool_data.vote_lockup_saturation = 0;
+    pool_data.checkpoints = CheckpointHistory::default();
+    pool_data.stats = PoolStats::default();
+    pool_data.reward_fee_bps = 0;
+    pool_data.treasury = Pubkey::default();
+    pool_data.deposit_fee_bps = 0;
+    pool_data.withdraw_fee_bps = 0;
+    pool_data.allowlist_root = [0; 32];
+    pool_data.accrual_mode = ACCRUAL_UNIX_TIME;
+    pool_data.apr_bps = 0;
+    pool_data.stake_mint = vault_data.mint;
+    pool_data.vault = *vault.key;
+    pool_data.pool_seed = 0;
+    reset_staked_rewards(pool_data, &clock)?;
 
     msg!(
-        "Unstaked {} tokens with {} rewards",
-        staking_data.amount_staked,
-        rewards
+        "Initialized pool with admin {} and vault {}",
+        admin_account.key,
+        vault.key
     );
     Ok(())
 }
+
+// Derive the address of the pool CreatePool makes for `stake_mint` and `seed`
+pub fn find_pool_address(program_id: &Pubkey, stake_mint: &Pubkey, seed: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[POOL_SEED, stake_mint.as_ref(), &seed.to_le_bytes()],
+        program_id,
+    )
+}
+
+// Derive the address of a created pool's principal vault
+pub fn find_pool_vault(program_id: &Pubkey, pool_key: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[POOL_VAULT_SEED, pool_key.as_ref()], program_id)
+}
+
+fn create_pool(program_id: &Pubkey, accounts: &[AccountInfo], seed: u64) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+    let stake_mint = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let pool_authority = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    if !admin_account.is_signer {
+        msg!("Admin signature is required");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if *token_program.key != spl_token::id() || stake_mint.owner != &spl_token::id() {
+        msg!("Stake mint must belong to the token program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let (pool_key, pool_bump) = find_pool_address(program_id, stake_mint.key, seed);
+    if *pool_account.key != pool_key {
+        msg!("Pool account does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    let (vault_key, vault_bump) = find_pool_vault(program_id, &pool_key);
+    if *vault.key != vault_key {
+        msg!("Vault does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    let (authority_key, _) = find_pool_authority(program_id, &pool_key);
+    if *pool_authority.key != authority_key {
+        msg!("Pool authority does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    let rent = Rent::get()?;
+    let seed_bytes = seed.to_le_bytes();
+    let pool_len = StakingPool::LEN;
+    invoke_signed(
+        &system_instruction::create_account(
+            admin_account.key,
+            pool_account.key,
+            rent.minimum_balance(pool_len),
+            pool_len as u64,
+            program_id,
+        ),
+        &[
+            admin_account.clone(),
+            pool_account.clone(),
+            system_program.clone(),
+        ],
+        &[&[
+            POOL_SEED,
+            stake_mint.key.as_ref(),
+            &seed_bytes,
+            &[pool_bump],
+        ]],
+    )?;
+
+    // The principal vault is owned by the pool authority, which signs every withdrawal
+    invoke_signed(
+        &system_instruction::create_account(
+            admin_account.key,
+            vault.key,
+            rent.minimum_balance(TokenAccount::LEN),
+            TokenAccount::LEN as u64,
+            &spl_token::id(),
+        ),
+        &[admin_account.clone(), vault.clone(), system_program.clone()],
+        &[&[POOL_VAULT_SEED, pool_key.as_ref(), &[vault_bump]]],
+    )?;
+    invoke(
+        &initialize_account3(&spl_token::id(), vault.key, stake_mint.key, &authority_key)?,
+        &[vault.clone(), stake_mint.clone(), token_program.clone()],
+    )?;
+
+    let mut pool_ref = pool_account.try_borrow_mut_data()?;
+    let pool_data = StakingPool::load_uninitialized(&mut pool_ref)?;
+    *pool_data = StakingPool {
+        discriminator: StakingPool::DISCRIMINATOR,
+        admin: *admin_account.key,
+        is_initialized: true.into(),
+        stake_mint: *stake_mint.key,
+        vault: vault_key,
+        pool_seed: seed,
+        ..StakingPool::default()
+    };
+    reset_staked_rewards(pool_data, &Clock::get()?)?;
+
+    msg!(
+        "Created pool {} for mint {} with seed {}",
+        pool_key,
+        stake_mint.key,
+        seed
+    );
+    Ok(())
+}
+
+// Pools initialized before vaults were recorded can't prove where deposits went, so
+// they only let existing stakes leave
+fn check_legacy_pool(pool_data: &StakingPool) -> ProgramResult {
+    if pool_data.vault == Pubkey::default() {
+        msg!("Pool has no recorded vault and only allows withdrawals");
+        return Err(StakingError::InvalidVault.into());
+    }
+    Ok(())
+}
+
+// Reject a principal vault that is not the one recorded on the pool
+fn check_vault(pool_data: &StakingPool, vault: &AccountInfo) -> ProgramResult {
+    check_legacy_pool(pool_data)?;
+    if *vault.key != pool_data.vault {
+        msg!("Vault {} does not belong to the pool", vault.key);
+        return Err(StakingError::InvalidVault.into());
+    }
+    Ok(())
+}
+
+// Signs transfers out of a pool's principal vault. Pools with a recorded vault sign
+// with the pool authority PDA. Legacy pools without one sign with the staking account,
+// which then has to be released by the caller before a transfer.
+struct VaultSigner<'b, 'a> {
+    authority: &'b AccountInfo<'a>,
+    pool_key: &'b Pubkey,
+    bump: Option<u8>,
+}
+
+impl<'a> VaultSigner<'_, 'a> {
+    fn transfer(
+        &self,
+        vault: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+        amount: u64,
+    ) -> ProgramResult {
+        let transfer_ix = transfer(
+            token_program.key,
+            vault.key,
+            destination.key,
+            self.authority.key,
+            &[],
+            amount,
+        )?;
+        let account_infos = [
+            vault.clone(),
+            destination.clone(),
+            self.authority.clone(),
+            token_program.clone(),
+        ];
+        match self.bump {
+            Some(bump) => invoke_signed(
+                &transfer_ix,
+                &account_infos,
+                &[&[POOL_AUTHORITY_SEED, self.pool_key.as_ref(), &[bump]]],
+            ),
+            None => invoke(&transfer_ix, &account_infos),
+        }
+    }
+}
+
+// Check the vault and pick its signer. Pools with a recorded vault take the pool
+// authority as the next account.
+fn next_vault_signer<'b, 'a>(
+    program_id: &Pubkey,
+    pool_account: &'b AccountInfo<'a>,
+    pool_data: &StakingPool,
+    vault: &AccountInfo<'a>,
+    staking_account: &'b AccountInfo<'a>,
+    accounts_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+) -> Result<VaultSigner<'b, 'a>, ProgramError> {
+    if pool_data.vault == Pubkey::default() {
+        return Ok(VaultSigner {
+            authority: staking_account,
+            pool_key: pool_account.key,
+            bump: None,
+        });
+    }
+    check_vault(pool_data, vault)?;
+
+    let pool_authority = next_account_info(accounts_iter)?;
+    let (authority_key, bump) = find_pool_authority(program_id, pool_account.key);
+    if *pool_authority.key != authority_key {
+        msg!("Pool authority does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    Ok(VaultSigner {
+        authority: pool_authority,
+        pool_key: pool_account.key,
+        bump: Some(bump),
+    })
+}
+
+fn set_paused(program_id: &Pubkey, accounts: &[AccountInfo], paused: bool) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    check_admin(&pool_data, admin_account)?;
+
+    pool_data.is_paused = paused.into();
+
+    msg!("Pool paused: {}", paused);
+    Ok(())
+}
+
+fn set_emission_schedule(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    halving_period: u64,
+    segments: Vec<EmissionSegment>,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    check_admin(&pool_data, admin_account)?;
+
+    if segments.len() > MAX_EMISSION_SEGMENTS {
+        msg!(
+            "Emission schedule can hold at most {} segments",
+            MAX_EMISSION_SEGMENTS
+        );
+        return Err(StakingError::InvalidEmissionSchedule.into());
+    }
+
+    // Segments must be ordered so each one ends where the next begins
+    if segments
+        .windows(2)
+        .any(|pair| pair[0].start_time >= pair[1].start_time)
+    {
+        msg!("Emission segments must have strictly increasing start times");
+        return Err(StakingError::InvalidEmissionSchedule.into());
+    }
+
+    // Accrue under the old schedule up to now so it only reprices the future
+    let clock = accrual_clock(&pool_data, &Clock::get()?);
+    update_staked_rewards(&mut pool_data, clock)?;
+    pool_data.halving_period = halving_period;
+    pool_data.segment_count = segments.len() as u8;
+    pool_data.emission_schedule = [EmissionSegment::default(); MAX_EMISSION_SEGMENTS];
+    pool_data.emission_schedule[..segments.len()].copy_from_slice(&segments);
+
+    msg!(
+        "Set emission schedule with {} segments, halving period {}",
+        segments.len(),
+        halving_period
+    );
+    Ok(())
+}
+
+fn set_stake_limits(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    max_total_staked: u64,
+    max_per_user: u64,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    check_admin(&pool_data, admin_account)?;
+
+    pool_data.max_total_staked = max_total_staked;
+    pool_data.max_per_user = max_per_user;
+
+    msg!(
+        "Set stake limits: pool {}, per user {}",
+        max_total_staked,
+        max_per_user
+    );
+    Ok(())
+}
+
+fn set_stake_minimums(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    min_stake_amount: u64,
+    min_stake_duration: u64,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    check_admin(&pool_data, admin_account)?;
+
+    pool_data.min_stake_amount = min_stake_amount;
+    pool_data.min_stake_duration = min_stake_duration;
+
+    msg!(
+        "Set stake minimums: amount {}, duration {}",
+        min_stake_amount,
+        min_stake_duration
+    );
+    Ok(())
+}
+
+fn set_accrual_mode(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    accrual_mode: u8,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    check_admin(&pool_data, admin_account)?;
+
+    if accrual_mode > ACCRUAL_EPOCH {
+        msg!("Unknown accrual mode {}", accrual_mode);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Stored start and end times would be read in the wrong unit after a switch,
+    // including the end times of existing vesting schedules
+    if pool_data.total_staked > 0
+        || pool_data.stream_count > 0
+        || pool_data.segment_count > 0
+        || pool_data.vesting_period > 0
+        || pool_data.vesting_vault != Pubkey::default()
+    {
+        msg!(
+            "Accrual mode can only change before stakes, streams, emission segments or vesting exist"
+        );
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    pool_data.accrual_mode = accrual_mode;
+    reset_staked_rewards(&mut pool_data, &Clock::get()?)?;
+
+    msg!("Set accrual mode to {}", accrual_mode);
+    Ok(())
+}
+
+// Current time in the pool's accrual unit. Negative unix timestamps clamp to zero
+// rather than wrapping, and epoch pools count whole epochs.
+fn accrual_clock(pool_data: &StakingPool, clock: &Clock) -> u64 {
+    match pool_data.accrual_mode {
+        ACCRUAL_SLOT => clock.slot,
+        ACCRUAL_EPOCH => clock.epoch,
+        _ => clock.unix_timestamp.max(0) as u64,
+    }
+}
+
+fn set_apr(program_id: &Pubkey, accounts: &[AccountInfo], apr_bps: u32) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    check_admin(&pool_data, admin_account)?;
+
+    // Accrue at the old rate up to now before switching
+    let clock = accrual_clock(&pool_data, &Clock::get()?);
+    update_staked_rewards(&mut pool_data, clock)?;
+    pool_data.apr_bps = apr_bps;
+
+    msg!(
+        "Set APR to {} bps ({} per token per unit, scaled by 1e18)",
+        apr_bps,
+        apr_rate_per_unit(&pool_data)?
+    );
+    Ok(())
+}
+
+// Nominal length of one accrual unit. Slots use the default slot time and epochs
+// the cluster's epoch length.
+fn ms_per_unit(pool_data: &StakingPool) -> Result<u128, ProgramError> {
+    Ok(match pool_data.accrual_mode {
+        ACCRUAL_SLOT => DEFAULT_MS_PER_SLOT as u128,
+        ACCRUAL_EPOCH => {
+            (EpochSchedule::get()?.slots_per_epoch as u128) * DEFAULT_MS_PER_SLOT as u128
+        }
+        _ => 1000,
+    })
+}
+
+// Convert the pool's APR into a reward per token per accrual unit, scaled by RATE_PRECISION
+fn apr_rate_per_unit(pool_data: &StakingPool) -> Result<u128, ProgramError> {
+    Ok(
+        pool_data.apr_bps as u128 * ms_per_unit(pool_data)? * RATE_PRECISION
+            / (10_000 * MS_PER_YEAR),
+    )
+}
+
+// Annualized rate a position earns at `clock`, in basis points before boosts
+fn current_apr_bps(pool_data: &StakingPool, clock: u64) -> Result<u64, ProgramError> {
+    let units_per_year = MS_PER_YEAR / ms_per_unit(pool_data)?;
+    let per_year = if pool_data.segment_count > 0 {
+        emission_between(
+            pool_data,
+            clock,
+            clock.saturating_add(units_per_year as u64),
+        )
+    } else if pool_data.apr_bps > 0 {
+        return Ok(pool_data.apr_bps as u64);
+    } else {
+        LEGACY_REWARD_RATE as u128 * units_per_year
+    };
+    // Legacy and schedule rates pay rate / 1000 per staked token per unit
+    Ok(u64::try_from(per_year * 10_000 / 1000).unwrap_or(u64::MAX))
+}
+
+// Time from which a deposit made now starts accruing. Like native stake warmup,
+// epoch pools only activate stakes made after an epoch's first slot at the next
+// boundary, so rewards cover fully completed epochs only.
+fn activation_clock(pool_data: &StakingPool, clock: &Clock) -> Result<u64, ProgramError> {
+    let now = accrual_clock(pool_data, clock);
+    if pool_data.accrual_mode != ACCRUAL_EPOCH {
+        return Ok(now);
+    }
+
+    let epoch_schedule = EpochSchedule::get()?;
+    if clock.slot == epoch_schedule.get_first_slot_in_epoch(clock.epoch) {
+        Ok(now)
+    } else {
+        Ok(now + 1)
+    }
+}
+
+// Derive the PDA that signs for the pool's reward vaults
+pub fn find_pool_authority(program_id: &Pubkey, pool_key: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[POOL_AUTHORITY_SEED, pool_key.as_ref()], program_id)
+}
+
+fn add_reward_stream(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    reward_rate: u64,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+    let reward_vault = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    let clock = accrual_clock(&pool_data, &Clock::get()?);
+    check_admin(&pool_data, admin_account)?;
+
+    let index = pool_data.stream_count as usize;
+    if index >= MAX_REWARD_STREAMS {
+        msg!("Pool already has {} reward streams", MAX_REWARD_STREAMS);
+        return Err(StakingError::TooManyRewardStreams.into());
+    }
+
+    // The vault must be a token account controlled by the pool authority
+    if reward_vault.owner != &spl_token::id() {
+        msg!("Reward vault is not a token account");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let vault_data = TokenAccount::unpack(&reward_vault.data.borrow())?;
+    let (authority_key, _) = find_pool_authority(program_id, pool_account.key);
+    if vault_data.owner != authority_key {
+        msg!("Reward vault must be owned by the pool authority");
+        return Err(StakingError::InvalidRewardStream.into());
+    }
+
+    pool_data.reward_streams[index] = RewardStream {
+        mint: vault_data.mint,
+        vault: *reward_vault.key,
+        reward_rate,
+        reward_per_token_stored: PodU128::default(),
+        reward_per_token_next: PodU128::default(),
+        last_update_time: clock,
+    };
+    pool_data.stream_count += 1;
+
+    msg!(
+        "Added reward stream {} for mint {} at {} per second",
+        index,
+        vault_data.mint,
+        reward_rate
+    );
+    Ok(())
+}
+
+fn set_reward_stream_rate(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    index: u8,
+    reward_rate: u64,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    let clock = accrual_clock(&pool_data, &Clock::get()?);
+    check_admin(&pool_data, admin_account)?;
+
+    if index >= pool_data.stream_count {
+        msg!("Reward stream {} does not exist", index);
+        return Err(StakingError::InvalidRewardStream.into());
+    }
+
+    // Accrue at the old rate up to now before switching
+    update_reward_streams(&mut pool_data, clock);
+    pool_data.reward_streams[index as usize].reward_rate = reward_rate;
+
+    msg!("Set reward stream {} rate to {}", index, reward_rate);
+    Ok(())
+}
+
+fn set_receipt_mint(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+    let receipt_mint = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    check_admin(&pool_data, admin_account)?;
+
+    // Existing positions hold no receipts, so the mint can only be attached to an empty pool
+    if pool_data.total_staked != 0 || pool_data.receipt_mint != Pubkey::default() {
+        msg!("Receipt mint can only be set once, before anything is staked");
+        return Err(StakingError::InvalidReceiptMint.into());
+    }
+
+    if receipt_mint.owner != &spl_token::id() {
+        msg!("Receipt mint is not a token mint");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let mint_data = Mint::unpack(&receipt_mint.data.borrow())?;
+    let (authority_key, _) = find_pool_authority(program_id, pool_account.key);
+    if mint_data.mint_authority != Some(authority_key).into() || mint_data.supply != 0 {
+        msg!("Receipt mint must be empty and minted by the pool authority");
+        return Err(StakingError::InvalidReceiptMint.into());
+    }
+
+    pool_data.receipt_mint = *receipt_mint.key;
+
+    msg!("Set receipt mint {}", receipt_mint.key);
+    Ok(())
+}
+
+// Mint receipt tokens for a deposit, valued at the pool's current exchange rate.
+// Returns the number minted, zero when the pool has no receipt mint.
+fn mint_receipt<'a>(
+    program_id: &Pubkey,
+    pool_account: &AccountInfo<'a>,
+    pool_data: &StakingPool,
+    accounts_iter: &mut std::slice::Iter<AccountInfo<'a>>,
+    token_program: &AccountInfo<'a>,
+    owner: &Pubkey,
+    amount: u64,
+) -> Result<u64, ProgramError> {
+    if pool_data.receipt_mint == Pubkey::default() {
+        return Ok(0);
+    }
+
+    let receipt_mint = next_account_info(accounts_iter)?;
+    let receipt_account = next_account_info(accounts_iter)?;
+    let pool_authority = next_account_info(accounts_iter)?;
+
+    if *receipt_mint.key != pool_data.receipt_mint {
+        msg!("Receipt mint does not match the pool");
+        return Err(StakingError::InvalidReceiptMint.into());
+    }
+
+    // Receipts always go to the position owner
+    let receipt_data = TokenAccount::unpack(&receipt_account.data.borrow())?;
+    if receipt_data.owner != *owner {
+        msg!("Receipt account must be owned by the position owner");
+        return Err(StakingError::Unauthorized.into());
+    }
+
+    let (authority_key, authority_bump) = find_pool_authority(program_id, pool_account.key);
+    if *pool_authority.key != authority_key {
+        msg!("Pool authority does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    // The first depositor sets a 1:1 rate, later deposits mint pro rata to the supply
+    let supply = Mint::unpack(&receipt_mint.data.borrow())?.supply;
+    let receipt_amount = if supply == 0 || pool_data.total_staked == 0 {
+        amount
+    } else {
+        let minted = amount as u128 * supply as u128 / pool_data.total_staked as u128;
+        u64::try_from(minted).map_err(|_| ProgramError::ArithmeticOverflow)?
+    };
+
+    let mint_ix = mint_to(
+        token_program.key,
+        receipt_mint.key,
+        receipt_account.key,
+        pool_authority.key,
+        &[],
+        receipt_amount,
+    )?;
+    invoke_signed(
+        &mint_ix,
+        &[
+            receipt_mint.clone(),
+            receipt_account.clone(),
+            pool_authority.clone(),
+            token_program.clone(),
+        ],
+        &[&[
+            POOL_AUTHORITY_SEED,
+            pool_account.key.as_ref(),
+            &[authority_bump],
+        ]],
+    )?;
+
+    msg!("Minted {} receipt tokens", receipt_amount);
+    Ok(receipt_amount)
+}
+
+// Burn the receipt tokens minted for a position that is withdrawn in full. Compounding
+// grows the stake without minting, so the pool-wide rate would ask for more receipts
+// than were ever issued.
+fn burn_receipt<'a>(
+    pool_data: &StakingPool,
+    accounts_iter: &mut std::slice::Iter<AccountInfo<'a>>,
+    token_program: &AccountInfo<'a>,
+    user_account: &AccountInfo<'a>,
+    staking_data: &mut StakingAccount,
+) -> ProgramResult {
+    if pool_data.receipt_mint == Pubkey::default() {
+        return Ok(());
+    }
+
+    let receipt_mint = next_account_info(accounts_iter)?;
+    let receipt_account = next_account_info(accounts_iter)?;
+
+    if *receipt_mint.key != pool_data.receipt_mint {
+        msg!("Receipt mint does not match the pool");
+        return Err(StakingError::InvalidReceiptMint.into());
+    }
+
+    let receipt_amount = std::mem::take(&mut staking_data.receipt_amount);
+    if receipt_amount == 0 {
+        return Ok(());
+    }
+
+    let burn_ix = burn(
+        token_program.key,
+        receipt_account.key,
+        receipt_mint.key,
+        user_account.key,
+        &[],
+        receipt_amount,
+    )?;
+    invoke(
+        &burn_ix,
+        &[
+            receipt_account.clone(),
+            receipt_mint.clone(),
+            user_account.clone(),
+            token_program.clone(),
+        ],
+    )?;
+
+    msg!("Burned {} receipt tokens", receipt_amount);
+    Ok(())
+}
+
+fn set_vesting(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    vesting_period: u64,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+    let vesting_vault = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    check_admin(&pool_data, admin_account)?;
+
+    // Vested rewards are held by the pool authority until released
+    if vesting_vault.owner != &spl_token::id() {
+        msg!("Vesting vault is not a token account");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let vault_data = TokenAccount::unpack(&vesting_vault.data.borrow())?;
+    let (authority_key, _) = find_pool_authority(program_id, pool_account.key);
+    if vault_data.owner != authority_key {
+        msg!("Vesting vault must be owned by the pool authority");
+        return Err(StakingError::InvalidVestingAccount.into());
+    }
+
+    pool_data.vesting_period = vesting_period;
+    pool_data.vesting_vault = *vesting_vault.key;
+
+    msg!("Set reward vesting period to {} seconds", vesting_period);
+    Ok(())
+}
+
+// Add `amount` to the owner's vesting schedule. A vesting account tracks a single
+// linear schedule, not one per claim, so the end time becomes the amount-weighted
+// blend of the current end and a full period from now. Frequent claims therefore
+// don't push back rewards from earlier ones. Amounts already unlocked are settled first.
+fn record_vesting(
+    program_id: &Pubkey,
+    pool_account: &AccountInfo,
+    pool_data: &StakingPool,
+    (vesting_account, vesting_vault): (&AccountInfo, &AccountInfo),
+    owner: &Pubkey,
+    amount: u64,
+    clock: u64,
+) -> ProgramResult {
+    if *vesting_vault.key != pool_data.vesting_vault {
+        msg!("Vesting vault does not match the pool");
+        return Err(StakingError::InvalidVestingAccount.into());
+    }
+
+    if vesting_account.owner != program_id {
+        msg!("Vesting account does not have the correct program ID");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut vesting_data =
+        VestingAccount::try_from_slice(&vesting_account.data.borrow()).map_err(|_| {
+            msg!("Failed to deserialize vesting account data");
+            ProgramError::InvalidAccountData
+        })?;
+
+    if !vesting_data.is_initialized {
+        vesting_data = VestingAccount {
+            owner: *owner,
+            pool: *pool_account.key,
+            locked_amount: 0,
+            unlocked_amount: 0,
+            last_update_time: clock,
+            end_time: clock,
+            is_initialized: true,
+        };
+    } else if vesting_data.owner != *owner || vesting_data.pool != *pool_account.key {
+        msg!("Vesting account belongs to another owner or pool");
+        return Err(StakingError::InvalidVestingAccount.into());
+    }
+
+    vesting_data.unlock(clock);
+    let locked = vesting_data.locked_amount as u128;
+    let new_locked = locked + amount as u128;
+    let new_end = clock.saturating_add(pool_data.vesting_period);
+    // Rounds up so integer division never shortens the blended schedule
+    let weighted = (locked * vesting_data.end_time as u128)
+        .checked_add(amount as u128 * new_end as u128)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    vesting_data.end_time = weighted.div_ceil(new_locked.max(1)) as u64;
+    vesting_data.locked_amount =
+        u64::try_from(new_locked).map_err(|_| ProgramError::ArithmeticOverflow)?;
+
+    vesting_data.serialize(&mut &mut vesting_account.data.borrow_mut()[..])?;
+
+    msg!("Vesting {} rewards until {}", amount, vesting_data.end_time);
+    Ok(())
+}
+
+fn set_referral_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    referral_bps: u16,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+    let referral_vault = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    check_admin(&pool_data, admin_account)?;
+
+    if referral_bps > 10_000 {
+        msg!("Referral share cannot exceed 10000 basis points");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Referral rewards are paid by the pool authority on top of staker rewards
+    if referral_vault.owner != &spl_token::id() {
+        msg!("Referral vault is not a token account");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let vault_data = TokenAccount::unpack(&referral_vault.data.borrow())?;
+    let (authority_key, _) = find_pool_authority(program_id, pool_account.key);
+    if vault_data.owner != authority_key {
+        msg!("Referral vault must be owned by the pool authority");
+        return Err(StakingError::InvalidReferralAccount.into());
+    }
+
+    pool_data.referral_bps = referral_bps;
+    pool_data.referral_vault = *referral_vault.key;
+
+    msg!("Set referral share to {} bps", referral_bps);
+    Ok(())
+}
+
+// Credit the position's referrer with their share of `rewards`, on top of what the staker receives
+fn credit_referrer(
+    program_id: &Pubkey,
+    pool_account: &AccountInfo,
+    pool_data: &StakingPool,
+    referrer: &Pubkey,
+    accounts_iter: &mut std::slice::Iter<AccountInfo>,
+    rewards: u64,
+) -> ProgramResult {
+    if *referrer == Pubkey::default() || pool_data.referral_bps == 0 {
+        return Ok(());
+    }
+
+    let referral_account = next_account_info(accounts_iter)?;
+    if referral_account.owner != program_id {
+        msg!("Referral account does not have the correct program ID");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut referral_data = ReferralAccount::try_from_slice(&referral_account.data.borrow())
+        .map_err(|_| {
+            msg!("Failed to deserialize referral account data");
+            ProgramError::InvalidAccountData
+        })?;
+
+    if !referral_data.is_initialized {
+        referral_data = ReferralAccount {
+            referrer: *referrer,
+            pool: *pool_account.key,
+            claimable: 0,
+            is_initialized: true,
+        };
+    } else if referral_data.referrer != *referrer || referral_data.pool != *pool_account.key {
+        msg!("Referral account belongs to another referrer or pool");
+        return Err(StakingError::InvalidReferralAccount.into());
+    }
+
+    let share = rewards as u128 * pool_data.referral_bps as u128 / 10_000;
+    referral_data.claimable = referral_data
+        .claimable
+        .checked_add(share as u64)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+
+    referral_data.serialize(&mut &mut referral_account.data.borrow_mut()[..])?;
+
+    msg!("Credited {} referral rewards to {}", share, referrer);
+    Ok(())
+}
+
+fn set_boost_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    boost_multiplier_bps: u16,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+    let collection_mint = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    check_admin(&pool_data, admin_account)?;
+
+    if boost_multiplier_bps != 0 && boost_multiplier_bps < 10_000 {
+        msg!("Boost multiplier must be at least 10000 basis points");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    pool_data.boost_collection = *collection_mint.key;
+    pool_data.boost_multiplier_bps = boost_multiplier_bps;
+
+    msg!(
+        "Set boost of {} bps for collection {}",
+        boost_multiplier_bps,
+        collection_mint.key
+    );
+    Ok(())
+}
+
+fn set_voter_weight_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    vote_lockup_bonus_bps: u16,
+    vote_lockup_saturation: u64,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    check_admin(&pool_data, admin_account)?;
+
+    if vote_lockup_bonus_bps != 0 && vote_lockup_saturation == 0 {
+        msg!("A lockup bonus needs a non-zero saturation period");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    pool_data.vote_lockup_bonus_bps = vote_lockup_bonus_bps;
+    pool_data.vote_lockup_saturation = vote_lockup_saturation;
+
+    msg!(
+        "Set voter weight lockup bonus to {} bps over {} seconds",
+        vote_lockup_bonus_bps,
+        vote_lockup_saturation
+    );
+    Ok(())
+}
+
+fn set_voter_weight_realm(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    realm: Pubkey,
+    governing_token_mint: Pubkey,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    check_admin(&pool_data, admin_account)?;
+
+    // Votes are weighted in the staked token, so created pools can only back their own mint
+    if pool_data.stake_mint != Pubkey::default() && governing_token_mint != pool_data.stake_mint {
+        msg!("Governing token mint must be the pool's stake mint");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    pool_data.voter_weight_realm = realm;
+    pool_data.voter_weight_mint = governing_token_mint;
+
+    msg!(
+        "Pinned voter weight records to realm {} and mint {}",
+        realm,
+        governing_token_mint
+    );
+    Ok(())
+}
+
+// Voting power of an owner: their stake across every position that isn't held as a
+// token, plus a bonus that grows with time staked
+fn calculate_voter_weight(
+    pool_data: &StakingPool,
+    positions_data: &UserPositions,
+    clock: u64,
+) -> Result<u64, ProgramError> {
+    let voting_staked = positions_data.voting_staked();
+    let amount = voting_staked as u128;
+    if pool_data.vote_lockup_bonus_bps == 0 || amount == 0 {
+        return Ok(voting_staked);
+    }
+
+    let saturation = pool_data.vote_lockup_saturation as u128;
+    let staked_for = (clock.saturating_sub(positions_data.vote_start_time) as u128).min(saturation);
+    let bonus = amount * pool_data.vote_lockup_bonus_bps as u128 * staked_for / saturation / 10_000;
+    u64::try_from(amount + bonus).map_err(|_| ProgramError::ArithmeticOverflow)
+}
+
+// Drop the position's boost if its NFT is no longer in the recorded token account
+fn refresh_boost(
+    staking_data: &mut StakingAccount,
+    accounts_iter: &mut std::slice::Iter<AccountInfo>,
+) -> ProgramResult {
+    if staking_data.boost_bps == 0 {
+        return Ok(());
+    }
+
+    let boost_token_account = next_account_info(accounts_iter)?;
+    if *boost_token_account.key != staking_data.boost_token_account {
+        msg!("Boost token account does not match the position");
+        return Err(StakingError::InvalidBoostNft.into());
+    }
+
+    let still_held = boost_token_account.owner == &spl_token::id()
+        && TokenAccount::unpack(&boost_token_account.data.borrow())
+            .map(|holder| {
+                holder.mint == staking_data.boost_mint
+                    && holder.amount == 1
+                    && holder.owner == staking_data.user
+            })
+            .unwrap_or(false);
+    if !still_held {
+        msg!("Boost NFT is gone, removing boost");
+        staking_data.boost_bps = 0;
+        staking_data.boost_start_time = 0;
+        staking_data.boost_mint = Pubkey::default();
+        staking_data.boost_token_account = Pubkey::default();
+    }
+    Ok(())
+}
+
+// Bring every stream's accumulator up to `clock`
+fn update_reward_streams(pool_data: &mut StakingPool, clock: u64) {
+    let total_staked = pool_data.total_staked;
+    let is_epoch = pool_data.accrual_mode == ACCRUAL_EPOCH;
+    for stream in pool_data.reward_streams[..pool_data.stream_count as usize].iter_mut() {
+        if clock <= stream.last_update_time {
+            continue;
+        }
+        let accrued = |units: u64| {
+            if total_staked > 0 {
+                stream.reward_rate as u128 * units as u128 * REWARD_PRECISION / total_staked as u128
+            } else {
+                0
+            }
+        };
+        // Epochs are priced when first touched, so the one after the last update is fixed
+        let stored = if is_epoch {
+            stream.reward_per_token_next.get() + accrued(clock - stream.last_update_time - 1)
+        } else {
+            stream.reward_per_token_stored.get() + accrued(clock - stream.last_update_time)
+        };
+        stream.reward_per_token_stored = stored.into();
+        stream.reward_per_token_next = (stored + accrued(1)).into();
+        stream.last_update_time = clock;
+    }
+}
+
+// Re-price the current unit of every stream against the current stake. Only valid
+// before any deposit has snapshotted the end of the unit.
+fn reprice_reward_streams(pool_data: &mut StakingPool) {
+    let total_staked = pool_data.total_staked;
+    for stream in pool_data.reward_streams[..pool_data.stream_count as usize].iter_mut() {
+        let accrued = if total_staked > 0 {
+            stream.reward_rate as u128 * REWARD_PRECISION / total_staked as u128
+        } else {
+            0
+        };
+        stream.reward_per_token_next = (stream.reward_per_token_stored.get() + accrued).into();
+    }
+}
+
+// Move a position's share of every stream into its owed balances
+fn settle_reward_streams(
+    pool_data: &StakingPool,
+    staking_data: &mut StakingAccount,
+) -> ProgramResult {
+    for (i, stream) in pool_data.reward_streams[..pool_data.stream_count as usize]
+        .iter()
+        .enumerate()
+    {
+        // Snapshots ahead of the accumulator belong to deposits that are not active yet
+        let stored = stream.reward_per_token_stored.get();
+        let paid = staking_data.reward_per_token_paid[i].get();
+        if stored <= paid {
+            continue;
+        }
+        let earned = staking_data.amount_staked as u128 * (stored - paid) / REWARD_PRECISION;
+        let earned = u64::try_from(earned).map_err(|_| ProgramError::ArithmeticOverflow)?;
+        staking_data.rewards_owed[i] = staking_data.rewards_owed[i]
+            .checked_add(earned)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        staking_data.reward_per_token_paid[i] = stored.into();
+    }
+    Ok(())
+}
+
+// Integrate the reward rate over [from, to), returning the sum of rate * seconds
+fn emission_between(pool_data: &StakingPool, from: u64, to: u64) -> u128 {
+    let segments = &pool_data.emission_schedule[..pool_data.segment_count as usize];
+    let mut total: u128 = 0;
+
+    for (i, segment) in segments.iter().enumerate() {
+        let is_last = i + 1 == segments.len();
+        let segment_end = if is_last {
+            u64::MAX
+        } else {
+            segments[i + 1].start_time
+        };
+
+        let start = from.max(segment.start_time);
+        let end = to.min(segment_end);
+        if start >= end {
+            continue;
+        }
+
+        if !is_last || pool_data.halving_period == 0 {
+            total += segment.reward_rate as u128 * (end - start) as u128;
+            continue;
+        }
+
+        // The last segment halves every period, so walk the periods we overlap
+        let period = pool_data.halving_period;
+        let mut halvings = (start - segment.start_time) / period;
+        let mut cursor = start;
+        while cursor < end && halvings < 64 {
+            let rate = segment.reward_rate >> halvings;
+            if rate == 0 {
+                break;
+            }
+            let period_end = segment
+                .start_time
+                .saturating_add((halvings + 1).saturating_mul(period))
+                .min(end);
+            total += rate as u128 * (period_end - cursor) as u128;
+            cursor = period_end;
+            halvings += 1;
+        }
+    }
+
+    total
+}
+
+fn set_reward_fee(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    reward_fee_bps: u16,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+    let treasury = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    check_admin(&pool_data, admin_account)?;
+
+    if reward_fee_bps > 10_000 {
+        msg!("Reward fee cannot exceed 10000 basis points");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if treasury.owner != &spl_token::id() {
+        msg!("Treasury is not a token account");
+        return Err(StakingError::InvalidTreasury.into());
+    }
+
+    pool_data.reward_fee_bps = reward_fee_bps;
+    pool_data.treasury = *treasury.key;
+
+    msg!(
+        "Set reward fee to {} bps paid to treasury {}",
+        reward_fee_bps,
+        treasury.key
+    );
+    Ok(())
+}
+
+fn set_transfer_fees(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    deposit_fee_bps: u16,
+    withdraw_fee_bps: u16,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+    let treasury = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    check_admin(&pool_data, admin_account)?;
+
+    if deposit_fee_bps > 10_000 || withdraw_fee_bps > 10_000 {
+        msg!("Transfer fees cannot exceed 10000 basis points");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if treasury.owner != &spl_token::id() {
+        msg!("Treasury is not a token account");
+        return Err(StakingError::InvalidTreasury.into());
+    }
+
+    pool_data.deposit_fee_bps = deposit_fee_bps;
+    pool_data.withdraw_fee_bps = withdraw_fee_bps;
+    pool_data.treasury = *treasury.key;
+
+    msg!(
+        "Set deposit fee to {} bps and withdraw fee to {} bps paid to treasury {}",
+        deposit_fee_bps,
+        withdraw_fee_bps,
+        treasury.key
+    );
+    Ok(())
+}
+
+fn set_allowlist_root(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    allowlist_root: [u8; 32],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    check_admin(&pool_data, admin_account)?;
+
+    pool_data.allowlist_root = allowlist_root;
+
+    msg!(
+        "Set allowlist root to {}",
+        Pubkey::new_from_array(allowlist_root)
+    );
+    Ok(())
+}
+
+// Check that (owner, max_allocation) is a leaf of the pool's allowlist.
+// Pairs are hashed in sorted order, so proofs carry no left/right flags.
+fn verify_allowlist(
+    pool_data: &StakingPool,
+    owner: &Pubkey,
+    allowlist: Option<&(u64, Vec<[u8; 32]>)>,
+) -> ProgramResult {
+    if pool_data.allowlist_root == [0; 32] {
+        return Ok(());
+    }
+
+    let (max_allocation, proof) = allowlist.ok_or_else(|| {
+        msg!("Pool is allowlisted, stake with a merkle proof");
+        StakingError::NotAllowlisted
+    })?;
+
+    let mut node = keccak::hashv(&[owner.as_ref(), &max_allocation.to_le_bytes()]).to_bytes();
+    for sibling in proof {
+        node = if node <= *sibling {
+            keccak::hashv(&[&node, sibling]).to_bytes()
+        } else {
+            keccak::hashv(&[sibling, &node]).to_bytes()
+        };
+    }
+
+    if node != pool_data.allowlist_root {
+        msg!("Merkle proof does not match the allowlist for {}", owner);
+        return Err(StakingError::NotAllowlisted.into());
+    }
+    Ok(())
+}
+
+// Take the treasury account from the iterator when a fee may be charged
+fn next_treasury<'a, 'b>(
+    pool_data: &StakingPool,
+    accounts_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+    needed: bool,
+) -> Result<Option<&'b AccountInfo<'a>>, ProgramError> {
+    if !needed {
+        return Ok(None);
+    }
+
+    let treasury = next_account_info(accounts_iter)?;
+    if *treasury.key != pool_data.treasury {
+        msg!("Treasury does not match the pool");
+        return Err(StakingError::InvalidTreasury.into());
+    }
+    Ok(Some(treasury))
+}
+
+// Portion of `amount` owed to the treasury at `fee_bps`
+fn fee_for(amount: u64, fee_bps: u16) -> u64 {
+    (amount as u128 * fee_bps as u128 / 10_000) as u64
+}
+
+// Move `fee` tokens from `source` to the treasury
+fn transfer_to_treasury<'a>(
+    source: &AccountInfo<'a>,
+    treasury: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    token_program: &AccountInfo<'a>,
+    fee: u64,
+) -> ProgramResult {
+    if fee == 0 {
+        return Ok(());
+    }
+
+    let transfer_ix = transfer(
+        token_program.key,
+        source.key,
+        treasury.key,
+        authority.key,
+        &[],
+        fee,
+    )?;
+    invoke(
+        &transfer_ix,
+        &[
+            source.clone(),
+            treasury.clone(),
+            authority.clone(),
+            token_program.clone(),
+        ],
+    )
+}
+
+// Send the protocol's cut of `rewards` from the vault to the treasury.
+// Returns the rewards left for the staker and the fee taken.
+fn take_reward_fee<'a>(
+    pool_data: &mut StakingPool,
+    treasury: Option<&AccountInfo<'a>>,
+    vault_signer: &VaultSigner<'_, 'a>,
+    token_account: &AccountInfo<'a>,
+    token_program: &AccountInfo<'a>,
+    rewards: u64,
+) -> Result<(u64, u64), ProgramError> {
+    let treasury = match treasury {
+        Some(treasury) if pool_data.reward_fee_bps > 0 => treasury,
+        _ => return Ok((rewards, 0)),
+    };
+
+    let fee = fee_for(rewards, pool_data.reward_fee_bps);
+    if fee > 0 {
+        vault_signer.transfer(token_account, treasury, token_program, fee)?;
+    }
+    pool_data.stats.total_reward_fees = pool_data.stats.total_reward_fees.saturating_add(fee);
+
+    Ok((rewards - fee, fee))
+}
+
+// Checkpoint the position and pool balances after they change. Checkpoints are kept in
+// unix time rather than the accrual unit so GetStakeAt reads the same on every pool.
+fn record_checkpoints(
+    pool_data: &mut StakingPool,
+    staking_data: &mut StakingAccount,
+) -> ProgramResult {
+    let timestamp = Clock::get()?.unix_timestamp.max(0) as u64;
+    staking_data
+        .checkpoints
+        .push(timestamp, staking_data.amount_staked);
+    pool_data
+        .checkpoints
+        .push(timestamp, pool_data.total_staked);
+    Ok(())
+}
+
+// Staked-token rewards one token earns over [from, to), scaled by RATE_PRECISION.
+// Pools without a schedule pay their APR, or the legacy constant rate.
+fn staked_reward_per_token_between(
+    pool_data: &StakingPool,
+    from: u64,
+    to: u64,
+) -> Result<u128, ProgramError> {
+    if to <= from {
+        return Ok(0);
+    }
+    let units = (to - from) as u128;
+    Ok(if pool_data.segment_count > 0 {
+        emission_between(pool_data, from, to).saturating_mul(RATE_PRECISION) / 1000
+    } else if pool_data.apr_bps > 0 {
+        apr_rate_per_unit(pool_data)?.saturating_mul(units)
+    } else {
+        (LEGACY_REWARD_RATE as u128 * units).saturating_mul(RATE_PRECISION) / 1000
+    })
+}
+
+// Restart the staked-token accumulator at the current time in the pool's unit
+fn reset_staked_rewards(pool_data: &mut StakingPool, clock: &Clock) -> ProgramResult {
+    let now = accrual_clock(pool_data, clock);
+    pool_data.staked_reward_per_token = PodU128::default();
+    pool_data.staked_reward_per_token_next =
+        staked_reward_per_token_between(pool_data, now, now + 1)?.into();
+    pool_data.staked_reward_last_update = now;
+    Ok(())
+}
+
+// Bring the staked-token accumulator up to `clock` at the rates in force so far
+fn update_staked_rewards(pool_data: &mut StakingPool, clock: u64) -> ProgramResult {
+    let last_update = pool_data.staked_reward_last_update;
+    if clock <= last_update {
+        return Ok(());
+    }
+
+    let stored = if pool_data.accrual_mode == ACCRUAL_EPOCH {
+        pool_data.staked_reward_per_token_next.get()
+            + staked_reward_per_token_between(pool_data, last_update + 1, clock)?
+    } else {
+        pool_data.staked_reward_per_token.get()
+            + staked_reward_per_token_between(pool_data, last_update, clock)?
+    };
+    pool_data.staked_reward_per_token = stored.into();
+    pool_data.staked_reward_per_token_next =
+        (stored + staked_reward_per_token_between(pool_data, clock, clock + 1)?).into();
+    pool_data.staked_reward_last_update = clock;
+    Ok(())
+}
+
+// Move a position's staked-token rewards since its last snapshot into its owed balance.
+// Snapshots ahead of the accumulator belong to deposits that are not active yet.
+fn settle_staked_rewards(
+    pool_data: &StakingPool,
+    staking_data: &mut StakingAccount,
+) -> ProgramResult {
+    let stored = pool_data.staked_reward_per_token.get();
+    let paid = staking_data.staked_reward_per_token_paid.get();
+    if stored <= paid {
+        return Ok(());
+    }
+
+    let earned = (staking_data.amount_staked as u128)
+        .checked_mul(stored - paid)
+        .ok_or(ProgramError::ArithmeticOverflow)?
+        / RATE_PRECISION;
+    // Boosts apply to whatever accrues while they are recorded on the position
+    let earned = if staking_data.boost_bps > 10_000 {
+        earned * staking_data.boost_bps as u128 / 10_000
+    } else {
+        earned
+    };
+    let earned = u64::try_from(earned).map_err(|_| ProgramError::ArithmeticOverflow)?;
+    staking_data.staked_rewards_owed = staking_data
+        .staked_rewards_owed
+        .checked_add(earned)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    staking_data.staked_reward_per_token_paid = stored.into();
+    Ok(())
+}
+
+// Snapshot for a settled position that grows by `amount`, weighted so the new amount
+// only earns from `active_value` on. Rounds up so it never pays for time before activation.
+fn blend_snapshot(
+    paid: u128,
+    active_value: u128,
+    amount: u64,
+    new_amount: u64,
+) -> Result<u128, ProgramError> {
+    if active_value <= paid || new_amount == 0 {
+        return Ok(paid);
+    }
+    let shift = (amount as u128)
+        .checked_mul(active_value - paid)
+        .ok_or(ProgramError::ArithmeticOverflow)?
+        .div_ceil(new_amount as u128);
+    Ok(paid + shift)
+}
+
+// Add `amount` to a settled position, earning staked-token and stream rewards from
+// `active_from`, the current or the next unit
+fn add_to_position(
+    pool_data: &StakingPool,
+    staking_data: &mut StakingAccount,
+    amount: u64,
+    active_from: u64,
+) -> ProgramResult {
+    let new_amount = staking_data
+        .amount_staked
+        .checked_add(amount)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    // An empty position starts over from the active value. Blending would keep a
+    // snapshot left over from before the accumulator was reset, which is ahead of it.
+    let snapshot = |paid: PodU128, active_value: u128| {
+        if staking_data.amount_staked == 0 {
+            Ok(active_value)
+        } else {
+            blend_snapshot(paid.get(), active_value, amount, new_amount)
+        }
+    };
+
+    let staked_value = if active_from > pool_data.staked_reward_last_update {
+        pool_data.staked_reward_per_token_next.get()
+    } else {
+        pool_data.staked_reward_per_token.get()
+    };
+    staking_data.staked_reward_per_token_paid =
+        snapshot(staking_data.staked_reward_per_token_paid, staked_value)?.into();
+
+    for (i, stream) in pool_data.reward_streams[..pool_data.stream_count as usize]
+        .iter()
+        .enumerate()
+    {
+        let stream_value = if active_from > stream.last_update_time {
+            stream.reward_per_token_next.get()
+        } else {
+            stream.reward_per_token_stored.get()
+        };
+        staking_data.reward_per_token_paid[i] =
+            snapshot(staking_data.reward_per_token_paid[i], stream_value)?.into();
+    }
+
+    staking_data.amount_staked = new_amount;
+    Ok(())
+}
+
+// Rewards are forfeited until a position has been staked for this long since `lock_start`
+fn min_duration(pool_data: &StakingPool, staking_data: &StakingAccount) -> u64 {
+    pool_data.min_stake_duration.max(staking_data.lock_duration)
+}
+
+// Derive the record of the position a boost NFT is applied to in a pool
+pub fn find_boost_record(
+    program_id: &Pubkey,
+    pool_key: &Pubkey,
+    nft_mint: &Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[BOOST_RECORD_SEED, pool_key.as_ref(), nft_mint.as_ref()],
+        program_id,
+    )
+}
+
+// Derive the account counting `owner`'s positions in a pool
+pub fn find_user_positions(program_id: &Pubkey, pool_key: &Pubkey, owner: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[USER_POSITIONS_SEED, pool_key.as_ref(), owner.as_ref()],
+        program_id,
+    )
+}
+
+// Derive the voter weight record of `owner` for a realm and governing token mint
+pub fn find_voter_weight_record(
+    program_id: &Pubkey,
+    realm: &Pubkey,
+    governing_token_mint: &Pubkey,
+    owner: &Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            VOTER_WEIGHT_RECORD_SEED,
+            realm.as_ref(),
+            governing_token_mint.as_ref(),
+            owner.as_ref(),
+        ],
+        program_id,
+    )
+}
+
+// Derive the address of `owner`'s position number `index` in a pool
+pub fn find_position_address(
+    program_id: &Pubkey,
+    pool_key: &Pubkey,
+    owner: &Pubkey,
+    index: u64,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            POSITION_SEED,
+            pool_key.as_ref(),
+            owner.as_ref(),
+            &index.to_le_bytes(),
+        ],
+        program_id,
+    )
+}
+
+fn open_position(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    lock_duration: u64,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let user_positions = next_account_info(accounts_iter)?;
+    let staking_account = next_account_info(accounts_iter)?;
+    let user_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    let pool_data = load_pool(program_id, pool_account)?;
+    let clock = accrual_clock(&pool_data, &Clock::get()?);
+
+    if !user_account.is_signer {
+        msg!("Owner signature is required");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    // A position PDA can't sign for a legacy vault, so nothing could ever leave it
+    check_legacy_pool(&pool_data)?;
+
+    // The counter is created with the user's first position
+    let mut positions_data = load_user_positions(
+        program_id,
+        pool_account,
+        user_positions,
+        user_account.key,
+        Some((user_account, system_program)),
+    )?;
+    let index = create_position(
+        program_id,
+        pool_account,
+        staking_account,
+        &mut positions_data,
+        user_account.key,
+        (user_account, system_program),
+        clock,
+    )?;
+    load_position(staking_account)?.lock_duration = lock_duration;
+    positions_data.serialize(&mut &mut user_positions.data.borrow_mut()[..])?;
+
+    msg!(
+        "Opened position {} for {} with a lock of {}",
+        index,
+        user_account.key,
+        lock_duration
+    );
+    Ok(())
+}
+
+// Create `owner`'s next position in the pool at its PDA, paid for by `payer`, and count
+// it. Returns the new position's index; the caller saves the counter.
+fn create_position<'a>(
+    program_id: &Pubkey,
+    pool_account: &AccountInfo<'a>,
+    staking_account: &AccountInfo<'a>,
+    positions_data: &mut UserPositions,
+    owner: &Pubkey,
+    (payer, system_program): (&AccountInfo<'a>, &AccountInfo<'a>),
+    clock: u64,
+) -> Result<u64, ProgramError> {
+    let index = positions_data.position_count;
+    let (position_key, position_bump) =
+        find_position_address(program_id, pool_account.key, owner, index);
+    if *staking_account.key != position_key {
+        msg!("Position account does not match index {}", index);
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    let position_len = StakingAccount::LEN;
+    invoke_signed(
+        &system_instruction::create_account(
+            payer.key,
+            staking_account.key,
+            Rent::get()?.minimum_balance(position_len),
+            position_len as u64,
+            program_id,
+        ),
+        &[
+            payer.clone(),
+            staking_account.clone(),
+            system_program.clone(),
+        ],
+        &[&[
+            POSITION_SEED,
+            pool_account.key.as_ref(),
+            owner.as_ref(),
+            &index.to_le_bytes(),
+            &[position_bump],
+        ]],
+    )?;
+    let mut staking_ref = staking_account.try_borrow_mut_data()?;
+    let staking_data = StakingAccount::load_uninitialized(&mut staking_ref)?;
+    *staking_data = StakingAccount::new(*owner, *pool_account.key, clock);
+
+    positions_data.position_count += 1;
+    Ok(index)
+}
+
+// Load `owner`'s position counter and running stake total for a pool. An empty account
+// is created when a (payer, system program) pair is given, otherwise it must exist.
+fn load_user_positions<'a>(
+    program_id: &Pubkey,
+    pool_account: &AccountInfo<'a>,
+    user_positions: &AccountInfo<'a>,
+    owner: &Pubkey,
+    payer: Option<(&AccountInfo<'a>, &AccountInfo<'a>)>,
+) -> Result<UserPositions, ProgramError> {
+    let (positions_key, positions_bump) = find_user_positions(program_id, pool_account.key, owner);
+    if *user_positions.key != positions_key {
+        msg!("Position counter does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    if !user_positions.data_is_empty() {
+        if user_positions.owner != program_id {
+            msg!("Position counter does not have the correct program ID");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        return UserPositions::try_from_slice(&user_positions.data.borrow()).map_err(|_| {
+            msg!("Failed to deserialize position counter");
+            ProgramError::InvalidAccountData
+        });
+    }
+
+    let Some((payer, system_program)) = payer else {
+        msg!("Position counter for {} does not exist", owner);
+        return Err(ProgramError::UninitializedAccount);
+    };
+    let positions_data = UserPositions {
+        owner: *owner,
+        pool: *pool_account.key,
+        position_count: 0,
+        amount_staked: 0,
+        tokenized_staked: 0,
+        vote_start_time: 0,
+        is_initialized: true,
+    };
+    let positions_len = positions_data.try_to_vec()?.len();
+    invoke_signed(
+        &system_instruction::create_account(
+            payer.key,
+            user_positions.key,
+            Rent::get()?.minimum_balance(positions_len),
+            positions_len as u64,
+            program_id,
+        ),
+        &[
+            payer.clone(),
+            user_positions.clone(),
+            system_program.clone(),
+        ],
+        &[&[
+            USER_POSITIONS_SEED,
+            pool_account.key.as_ref(),
+            owner.as_ref(),
+            &[positions_bump],
+        ]],
+    )?;
+    Ok(positions_data)
+}
+
+// Borrow the position a deposit goes into. Positions are written in place, so the
+// account must already have been opened with OpenPosition or InitializeStake.
+fn load_deposit_position<'b>(
+    staking_account: &'b AccountInfo,
+    pool_account: &AccountInfo,
+) -> Result<RefMut<'b, StakingAccount>, ProgramError> {
+    if staking_account.data_is_empty() {
+        msg!("Staking account has no room for a position, open one first");
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+    let staking_data = load_position(staking_account)
+        .inspect_err(|_| msg!("Failed to deserialize staking account data"))?;
+
+    if !staking_data.is_initialized.get() {
+        msg!("Staking account is not initialized");
+        return Err(ProgramError::UninitializedAccount);
+    }
+    check_position_pool(&staking_data, pool_account)?;
+
+    Ok(staking_data)
+}
+
+// Validate a deposit against the pool limits and credit it to the position and to
+// the owner's running total
+fn record_deposit(
+    pool_data: &mut StakingPool,
+    staking_data: &mut StakingAccount,
+    positions_data: &mut UserPositions,
+    amount: u64,
+    clock: u64,
+    active_from: u64,
+) -> ProgramResult {
+    if amount == 0 {
+        msg!("Staking amount cannot be zero");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if amount < pool_data.min_stake_amount {
+        msg!(
+            "Staking amount {} is below the pool minimum of {}",
+            amount,
+            pool_data.min_stake_amount
+        );
+        return Err(StakingError::BelowMinimumStake.into());
+    }
+
+    let new_total_staked = pool_data
+        .total_staked
+        .checked_add(amount)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    if pool_data.max_total_staked != 0 && new_total_staked > pool_data.max_total_staked {
+        msg!(
+            "Staking {} would exceed the pool capacity of {}",
+            amount,
+            pool_data.max_total_staked
+        );
+        return Err(StakingError::PoolCapacityExceeded.into());
+    }
+
+    // The per-user cap covers everything the owner holds in the pool, across positions
+    let new_user_staked = positions_data
+        .amount_staked
+        .checked_add(amount)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    if pool_data.max_per_user != 0 && new_user_staked > pool_data.max_per_user {
+        msg!(
+            "Staking {} would exceed the per-user limit of {}",
+            amount,
+            pool_data.max_per_user
+        );
+        return Err(StakingError::UserStakeLimitExceeded.into());
+    }
+
+    // Settle rewards at the old balance before it changes
+    update_staked_rewards(pool_data, clock)?;
+    settle_staked_rewards(pool_data, staking_data)?;
+    update_reward_streams(pool_data, clock);
+    settle_reward_streams(pool_data, staking_data)?;
+
+    // Update staking details. A staker is counted once, however many positions they hold.
+    if staking_data.amount_staked == 0 {
+        staking_data.lock_start = active_from;
+    }
+    if positions_data.amount_staked == 0 {
+        pool_data.stats.active_stakers += 1;
+    }
+    pool_data.stats.total_deposited = pool_data.stats.total_deposited.saturating_add(amount);
+    add_to_position(pool_data, staking_data, amount, active_from)?;
+    // A clock that went backwards must never rewind the start
+    staking_data.stake_start_time = staking_data.stake_start_time.max(active_from);
+    pool_data.total_staked = new_total_staked;
+    positions_data.deposit(staking_data, amount, active_from)?;
+    // Stake active from the current epoch joins the epoch's stream pricing
+    if pool_data.accrual_mode == ACCRUAL_EPOCH && active_from == clock {
+        reprice_reward_streams(pool_data);
+    }
+    record_checkpoints(pool_data, staking_data)?;
+    Ok(())
+}
+
+fn stake_tokens(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+    referrer: Option<Pubkey>,
+    allowlist: Option<(u64, Vec<[u8; 32]>)>,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let staking_account = next_account_info(accounts_iter)?;
+    let user_account = next_account_info(accounts_iter)?;
+    let user_token_account = next_account_info(accounts_iter)?;
+    let token_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let user_positions = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    let sysvar_clock = Clock::get()?;
+    let clock = accrual_clock(&pool_data, &sysvar_clock);
+    let active_from = activation_clock(&pool_data, &sysvar_clock)?;
+    if pool_data.is_paused.get() {
+        msg!("Pool is paused, staking is disabled");
+        return Err(StakingError::PoolPaused.into());
+    }
+
+    // Ensure that the staking account belongs to this program
+    if staking_account.owner != program_id {
+        msg!("Staking account does not have the correct program ID");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // The staker signs for the deposit out of their token account
+    if !user_account.is_signer {
+        msg!("Staker signature is required");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let mut staking_data = load_deposit_position(staking_account, pool_account)?;
+
+    // Only the owner or a delegate allowed to restake can add to a position
+    if staking_data.user != *user_account.key {
+        check_position_authority(&staking_data, user_account, DELEGATE_RESTAKE)?;
+    }
+
+    // The referrer is recorded once, only on the owner's own signature, and can never be
+    // the position owner. A delegate could otherwise name itself and earn the referral share.
+    if let Some(referrer) = referrer {
+        if staking_data.referrer == Pubkey::default()
+            && *user_account.key == staking_data.user
+            && referrer != staking_data.user
+        {
+            staking_data.referrer = referrer;
+            msg!("Recorded referrer {}", referrer);
+        }
+    }
+    verify_allowlist(&pool_data, &staking_data.user, allowlist.as_ref())?;
+    let mut positions_data = load_user_positions(
+        program_id,
+        pool_account,
+        user_positions,
+        &staking_data.user,
+        Some((user_account, system_program)),
+    )?;
+    check_vault(&pool_data, token_account)?;
+
+    // Only the amount left after the deposit fee is credited to the position
+    let deposit_fee = fee_for(amount, pool_data.deposit_fee_bps);
+    let net_amount = amount - deposit_fee;
+
+    let receipt_amount = mint_receipt(
+        program_id,
+        pool_account,
+        &pool_data,
+        accounts_iter,
+        token_program,
+        &staking_data.user,
+        net_amount,
+    )?;
+    staking_data.receipt_amount = staking_data
+        .receipt_amount
+        .checked_add(receipt_amount)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    record_deposit(
+        &mut pool_data,
+        &mut staking_data,
+        &mut positions_data,
+        net_amount,
+        clock,
+        active_from,
+    )?;
+
+    // Allowlist leaves cap what the owner holds in the pool across all of their positions
+    if let Some((max_allocation, _)) = allowlist {
+        if max_allocation != 0 && positions_data.amount_staked > max_allocation {
+            msg!(
+                "Staking {} would exceed the allocation of {}",
+                net_amount,
+                max_allocation
+            );
+            return Err(StakingError::AllocationExceeded.into());
+        }
+    }
+
+    let treasury = next_treasury(&pool_data, accounts_iter, deposit_fee > 0)?;
+    if let Some(treasury) = treasury {
+        transfer_to_treasury(
+            user_token_account,
+            treasury,
+            user_account,
+            token_program,
+            deposit_fee,
+        )?;
+        pool_data.stats.total_deposit_fees = pool_data
+            .stats
+            .total_deposit_fees
+            .saturating_add(deposit_fee);
+    }
+
+    // Transfer tokens from the user's token account to the staking vault
+    let transfer_ix = transfer(
+        token_program.key,
+        user_token_account.key,
+        token_account.key,
+        user_account.key,
+        &[],
+        net_amount,
+    )?;
+    invoke(
+        &transfer_ix,
+        &[
+            user_token_account.clone(),
+            token_account.clone(),
+            user_account.clone(),
+            token_program.clone(),
+        ],
+    )?;
+
+    // The pool and position are written in place, only the running total is saved
+    positions_data.serialize(&mut &mut user_positions.data.borrow_mut()[..])?;
+
+    msg!(
+        "Staked {} tokens successfully! ({} fee)",
+        net_amount,
+        deposit_fee
+    );
+    Ok(())
+}
+
+fn stake_for(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    beneficiary: Pubkey,
+    amount: u64,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let staking_account = next_account_info(accounts_iter)?;
+    let funder_account = next_account_info(accounts_iter)?;
+    let funder_token_account = next_account_info(accounts_iter)?;
+    let token_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let user_positions = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    let sysvar_clock = Clock::get()?;
+    let clock = accrual_clock(&pool_data, &sysvar_clock);
+    let active_from = activation_clock(&pool_data, &sysvar_clock)?;
+    if pool_data.is_paused.get() {
+        msg!("Pool is paused, staking is disabled");
+        return Err(StakingError::PoolPaused.into());
+    }
+
+    if !funder_account.is_signer {
+        msg!("Funder signature is required");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    // Allowlisted pools only accept deposits that carry a proof
+    verify_allowlist(&pool_data, &beneficiary, None)?;
+    check_vault(&pool_data, token_account)?;
+
+    let mut positions_data = load_user_positions(
+        program_id,
+        pool_account,
+        user_positions,
+        &beneficiary,
+        Some((funder_account, system_program)),
+    )?;
+
+    // Payroll and airdrop flows can't collect the beneficiary's signature, so the funder
+    // may open the beneficiary's next position and pay its rent
+    if staking_account.data_is_empty() {
+        let index = create_position(
+            program_id,
+            pool_account,
+            staking_account,
+            &mut positions_data,
+            &beneficiary,
+            (funder_account, system_program),
+            clock,
+        )?;
+        msg!("Opened position {} for {}", index, beneficiary);
+    } else if staking_account.owner != program_id {
+        msg!("Staking account does not have the correct program ID");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // The funder pays, but the position always belongs to the beneficiary
+    let mut staking_data = load_deposit_position(staking_account, pool_account)?;
+    if staking_data.user != beneficiary {
+        msg!(
+            "Staking account is owned by {}, not the beneficiary",
+            staking_data.user
+        );
+        return Err(StakingError::Unauthorized.into());
+    }
+
+    // Without the beneficiary's signature a funder could reset the start time and
+    // voting age of a live position, so only empty positions can be funded
+    if staking_data.amount_staked > 0 {
+        msg!("StakeFor can only fund an empty position, the owner must top up with Stake");
+        return Err(StakingError::Unauthorized.into());
+    }
+
+    // Only the amount left after the deposit fee is credited to the position
+    let deposit_fee = fee_for(amount, pool_data.deposit_fee_bps);
+    let net_amount = amount - deposit_fee;
+
+    let receipt_amount = mint_receipt(
+        program_id,
+        pool_account,
+        &pool_data,
+        accounts_iter,
+        token_program,
+        &beneficiary,
+        net_amount,
+    )?;
+    staking_data.receipt_amount = staking_data
+        .receipt_amount
+        .checked_add(receipt_amount)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    record_deposit(
+        &mut pool_data,
+        &mut staking_data,
+        &mut positions_data,
+        net_amount,
+        clock,
+        active_from,
+    )?;
+
+    let treasury = next_treasury(&pool_data, accounts_iter, deposit_fee > 0)?;
+    if let Some(treasury) = treasury {
+        transfer_to_treasury(
+            funder_token_account,
+            treasury,
+            funder_account,
+            token_program,
+            deposit_fee,
+        )?;
+        pool_data.stats.total_deposit_fees = pool_data
+            .stats
+            .total_deposit_fees
+            .saturating_add(deposit_fee);
+    }
+
+    // Transfer tokens from the funder to the staking vault
+    let transfer_ix = transfer(
+        token_program.key,
+        funder_token_account.key,
+        token_account.key,
+        funder_account.key,
+        &[],
+        net_amount,
+    )?;
+    invoke(
+        &transfer_ix,
+        &[
+            funder_token_account.clone(),
+            token_account.clone(),
+            funder_account.clone(),
+            token_program.clone(),
+        ],
+    )?;
+
+    // The pool and position are written in place, only the running total is saved
+    positions_data.serialize(&mut &mut user_positions.data.borrow_mut()[..])?;
+
+    msg!(
+        "Staked {} tokens on behalf of {} ({} fee)",
+        net_amount,
+        beneficiary,
+        deposit_fee
+    );
+    Ok(())
+}
+
+fn unstake_tokens(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let staking_account = next_account_info(accounts_iter)?;
+    let user_account = next_account_info(accounts_iter)?;
+    let user_token_account = next_account_info(accounts_iter)?;
+    let token_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let user_positions = next_account_info(accounts_iter)?;
+
+    // Rewards are paid out on unstake, so a paused pool only allows emergency withdrawals
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    let clock = accrual_clock(&pool_data, &Clock::get()?);
+    if pool_data.is_paused.get() {
+        msg!("Pool is paused, use emergency withdraw to recover principal");
+        return Err(StakingError::PoolPaused.into());
+    }
+
+    // Ensure that the staking account belongs to this program
+    if staking_account.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // Deserialize staking account
+    let mut staking_data = load_position(staking_account)?;
+
+    if !staking_data.is_initialized.get() {
+        return Err(ProgramError::UninitializedAccount);
+    }
+    check_position_pool(&staking_data, pool_account)?;
+    let mut positions_data = load_user_positions(
+        program_id,
+        pool_account,
+        user_positions,
+        &staking_data.user,
+        None,
+    )?;
+
+    let vault_signer = next_vault_signer(
+        program_id,
+        pool_account,
+        &pool_data,
+        token_account,
+        staking_account,
+        accounts_iter,
+    )?;
+    check_position_owner(&staking_data, user_account, accounts_iter)?;
+    refresh_boost(&mut staking_data, accounts_iter)?;
+
+    update_staked_rewards(&mut pool_data, clock)?;
+    settle_staked_rewards(&pool_data, &mut staking_data)?;
+    update_reward_streams(&mut pool_data, clock);
+    settle_reward_streams(&pool_data, &mut staking_data)?;
+
+    // Positions withdrawn before the minimum duration forfeit their rewards
+    let rewards = if clock.saturating_sub(staking_data.lock_start)
+        < min_duration(&pool_data, &staking_data)
+    {
+        msg!("Minimum staking duration not reached, rewards forfeited");
+        staking_data.rewards_owed = [0; MAX_REWARD_STREAMS];
+        0
+    } else {
+        staking_data.staked_rewards_owed
+    };
+    staking_data.staked_rewards_owed = 0;
+    let principal = staking_data.amount_staked;
+    let withdraw_fee = fee_for(principal, pool_data.withdraw_fee_bps);
+    let treasury = next_treasury(
+        &pool_data,
+        accounts_iter,
+        pool_data.reward_fee_bps > 0 || withdraw_fee > 0,
+    )?;
+    let net_principal = principal - withdraw_fee;
+
+    burn_receipt(
+        &pool_data,
+        accounts_iter,
+        token_program,
+        user_account,
+        &mut staking_data,
+    )?;
+
+    // Reset staking account. The staker stops counting once their last position is empty.
+    positions_data.withdraw(&staking_data, principal);
+    if principal > 0 && positions_data.amount_staked == 0 {
+        pool_data.stats.active_stakers = pool_data.stats.active_stakers.saturating_sub(1);
+    }
+    pool_data.stats.total_withdrawn = pool_data.stats.total_withdrawn.saturating_add(principal);
+    pool_data.total_staked = pool_data.total_staked.saturating_sub(principal);
+    staking_data.amount_staked = 0;
+    staking_data.stake_start_time = 0;
+    staking_data.lock_start = 0;
+    record_checkpoints(&mut pool_data, &mut staking_data)?;
+
+    // Legacy vaults sign with the staking account, so the position is released
+    // before any tokens move
+    let referrer = staking_data.referrer;
+    drop(staking_data);
+
+    let (rewards, reward_fee) = take_reward_fee(
+        &mut pool_data,
+        treasury,
+        &vault_signer,
+        token_account,
+        token_program,
+        rewards,
+    )?;
+
+    // The withdraw fee comes out of the principal before it is paid back
+    if let Some(treasury) = treasury {
+        if withdraw_fee > 0 {
+            vault_signer.transfer(token_account, treasury, token_program, withdraw_fee)?;
+        }
+        pool_data.stats.total_withdraw_fees = pool_data
+            .stats
+            .total_withdraw_fees
+            .saturating_add(withdraw_fee);
+    }
+
+    // Vested pools pay the principal now and move the rewards into the vesting schedule
+    let mut total_amount = net_principal + rewards;
+    if pool_data.vesting_period > 0 {
+        let vesting_accounts = (
+            next_account_info(accounts_iter)?,
+            next_account_info(accounts_iter)?,
+        );
+        if rewards > 0 {
+            record_vesting(
+                program_id,
+                pool_account,
+                &pool_data,
+                vesting_accounts,
+                user_account.key,
+                rewards,
+                clock,
+            )?;
+
+            vault_signer.transfer(token_account, vesting_accounts.1, token_program, rewards)?;
+            total_amount = net_principal;
+        }
+    }
+
+    credit_referrer(
+        program_id,
+        pool_account,
+        &pool_data,
+        &referrer,
+        accounts_iter,
+        rewards,
+    )?;
+
+    vault_signer.transfer(
+        token_account,
+        user_token_account,
+        token_program,
+        total_amount,
+    )?;
+    pool_data.stats.total_rewards_paid = pool_data.stats.total_rewards_paid.saturating_add(rewards);
+
+    // The pool and position are written in place, only the running total is saved
+    positions_data.serialize(&mut &mut user_positions.data.borrow_mut()[..])?;
+
+    msg!(
+        "Unstaked {} tokens ({} fee) with {} rewards ({} fee)",
+        net_principal,
+        withdraw_fee,
+        rewards,
+        reward_fee
+    );
+    Ok(())
+}
+
+fn emergency_withdraw(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let staking_account = next_account_info(accounts_iter)?;
+    let user_account = next_account_info(accounts_iter)?;
+    let user_token_account = next_account_info(accounts_iter)?;
+    let token_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let user_positions = next_account_info(accounts_iter)?;
+
+    // Always allowed, even while the pool is paused
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    let clock = accrual_clock(&pool_data, &Clock::get()?);
+
+    // Ensure that the staking account belongs to this program
+    if staking_account.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // Deserialize staking account
+    let mut staking_data = load_position(staking_account)?;
+
+    if !staking_data.is_initialized.get() {
+        return Err(ProgramError::UninitializedAccount);
+    }
+    check_position_pool(&staking_data, pool_account)?;
+    let mut positions_data = load_user_positions(
+        program_id,
+        pool_account,
+        user_positions,
+        &staking_data.user,
+        None,
+    )?;
+
+    let vault_signer = next_vault_signer(
+        program_id,
+        pool_account,
+        &pool_data,
+        token_account,
+        staking_account,
+        accounts_iter,
+    )?;
+    check_position_owner(&staking_data, user_account, accounts_iter)?;
+
+    // Return the principal only, any accrued rewards are forfeited
+    let principal = staking_data.amount_staked;
+
+    burn_receipt(
+        &pool_data,
+        accounts_iter,
+        token_program,
+        user_account,
+        &mut staking_data,
+    )?;
+
+    // Reset staking account, dropping any unclaimed staked-token and stream rewards
+    update_staked_rewards(&mut pool_data, clock)?;
+    settle_staked_rewards(&pool_data, &mut staking_data)?;
+    update_reward_streams(&mut pool_data, clock);
+    settle_reward_streams(&pool_data, &mut staking_data)?;
+    staking_data.staked_rewards_owed = 0;
+    staking_data.rewards_owed = [0; MAX_REWARD_STREAMS];
+    positions_data.withdraw(&staking_data, principal);
+    if principal > 0 && positions_data.amount_staked == 0 {
+        pool_data.stats.active_stakers = pool_data.stats.active_stakers.saturating_sub(1);
+    }
+    pool_data.stats.total_withdrawn = pool_data.stats.total_withdrawn.saturating_add(principal);
+    pool_data.total_staked = pool_data.total_staked.saturating_sub(principal);
+    staking_data.amount_staked = 0;
+    staking_data.stake_start_time = 0;
+    staking_data.lock_start = 0;
+    record_checkpoints(&mut pool_data, &mut staking_data)?;
+
+    // Legacy vaults sign with the staking account, so the position is released
+    // before the principal moves
+    drop(staking_data);
+    vault_signer.transfer(token_account, user_token_account, token_program, principal)?;
+
+    // The pool and position are written in place, only the running total is saved
+    positions_data.serialize(&mut &mut user_positions.data.borrow_mut()[..])?;
+
+    msg!("Emergency withdrew {} tokens, rewards forfeited", principal);
+    Ok(())
+}
+
+fn claim_rewards(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let staking_account = next_account_info(accounts_iter)?;
+    let signer_account = next_account_info(accounts_iter)?;
+    let user_token_account = next_account_info(accounts_iter)?;
+    let token_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let pool_authority = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    let clock = accrual_clock(&pool_data, &Clock::get()?);
+    if pool_data.is_paused.get() {
+        msg!("Pool is paused, reward claims are disabled");
+        return Err(StakingError::PoolPaused.into());
+    }
+
+    // Ensure that the staking account belongs to this program
+    if staking_account.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // Deserialize staking account
+    let mut staking_data = load_position(staking_account)?;
+
+    if !staking_data.is_initialized.get() {
+        return Err(ProgramError::UninitializedAccount);
+    }
+    check_position_pool(&staking_data, pool_account)?;
+
+    let is_delegate =
+        check_position_signer(&staking_data, signer_account, accounts_iter, DELEGATE_CLAIM)?;
+
+    // Rewards in the staked token always go back to the position owner
+    let owner = if staking_data.position_mint == Pubkey::default() {
+        staking_data.user
+    } else {
+        *signer_account.key
+    };
+    if TokenAccount::unpack(&user_token_account.data.borrow())?.owner != owner {
+        msg!("Rewards can only be paid to the position owner");
+        return Err(StakingError::Unauthorized.into());
+    }
+
+    let (authority_key, authority_bump) = find_pool_authority(program_id, pool_account.key);
+    if *pool_authority.key != authority_key {
+        msg!("Pool authority does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    // Pools with a recorded vault reuse the pool authority to sign for it
+    let vault_signer = if pool_data.vault == Pubkey::default() {
+        VaultSigner {
+            authority: staking_account,
+            pool_key: pool_account.key,
+            bump: None,
+        }
+    } else {
+        check_vault(&pool_data, token_account)?;
+        VaultSigner {
+            authority: pool_authority,
+            pool_key: pool_account.key,
+            bump: Some(authority_bump),
+        }
+    };
+
+    // Pools that vest rewards route them through the owner's vesting schedule
+    let vesting_accounts = if pool_data.vesting_period > 0 {
+        Some((
+            next_account_info(accounts_iter)?,
+            next_account_info(accounts_iter)?,
+        ))
+    } else {
+        None
+    };
+
+    refresh_boost(&mut staking_data, accounts_iter)?;
+    update_staked_rewards(&mut pool_data, clock)?;
+    settle_staked_rewards(&pool_data, &mut staking_data)?;
+
+    // Rewards are only claimable once the minimum duration is reached, otherwise an
+    // early claim would keep what unstaking before then forfeits
+    let min_duration_reached =
+        clock.saturating_sub(staking_data.lock_start) >= min_duration(&pool_data, &staking_data);
+    let rewards = if min_duration_reached {
+        std::mem::take(&mut staking_data.staked_rewards_owed)
+    } else {
+        0
+    };
+    let treasury = next_treasury(&pool_data, accounts_iter, pool_data.reward_fee_bps > 0)?;
+
+    // Legacy vaults sign with the staking account, so the position is released while
+    // the staked-token rewards move
+    let referrer = staking_data.referrer;
+    drop(staking_data);
+
+    let (rewards, reward_fee) = take_reward_fee(
+        &mut pool_data,
+        treasury,
+        &vault_signer,
+        token_account,
+        token_program,
+        rewards,
+    )?;
+    credit_referrer(
+        program_id,
+        pool_account,
+        &pool_data,
+        &referrer,
+        accounts_iter,
+        rewards,
+    )?;
+    if rewards > 0 {
+        let destination = match vesting_accounts {
+            Some(vesting_accounts) => {
+                record_vesting(
+                    program_id,
+                    pool_account,
+                    &pool_data,
+                    vesting_accounts,
+                    &owner,
+                    rewards,
+                    clock,
+                )?;
+                vesting_accounts.1
+            }
+            None => user_token_account,
+        };
+        vault_signer.transfer(token_account, destination, token_program, rewards)?;
+        pool_data.stats.total_rewards_paid =
+            pool_data.stats.total_rewards_paid.saturating_add(rewards);
+    }
+
+    let mut staking_data = load_position(staking_account)?;
+    update_reward_streams(&mut pool_data, clock);
+    settle_reward_streams(&pool_data, &mut staking_data)?;
+
+    // Remaining accounts are (reward vault, destination) pairs in stream order
+    let authority_seeds: &[&[u8]] = &[
+        POOL_AUTHORITY_SEED,
+        pool_account.key.as_ref(),
+        &[authority_bump],
+    ];
+    for i in 0..pool_data.stream_count as usize {
+        let reward_vault = next_account_info(accounts_iter)?;
+        let destination = next_account_info(accounts_iter)?;
+
+        if *reward_vault.key != pool_data.reward_streams[i].vault {
+            msg!(
+                "Reward vault {} does not match stream {}",
+                reward_vault.key,
+                i
+            );
+            return Err(StakingError::InvalidRewardStream.into());
+        }
+
+        let owed = staking_data.rewards_owed[i];
+        if owed == 0 || !min_duration_reached {
+            continue;
+        }
+
+        // Delegates may only route stream rewards to accounts the owner holds
+        if is_delegate {
+            let destination_data = TokenAccount::unpack(&destination.data.borrow())?;
+            if destination_data.owner != staking_data.user {
+                msg!("Delegates cannot claim to a foreign account");
+                return Err(StakingError::Unauthorized.into());
+            }
+        }
+
+        let transfer_ix = transfer(
+            token_program.key,
+            reward_vault.key,
+            destination.key,
+            pool_authority.key,
+            &[],
+            owed,
+        )?;
+        invoke_signed(
+            &transfer_ix,
+            &[
+                reward_vault.clone(),
+                destination.clone(),
+                pool_authority.clone(),
+                token_program.clone(),
+            ],
+            &[authority_seeds],
+        )?;
+        staking_data.rewards_owed[i] = 0;
+
+        msg!("Claimed {} from reward stream {}", owed, i);
+    }
+
+    msg!("Claimed {} staking rewards ({} fee)", rewards, reward_fee);
+    Ok(())
+}
+
+fn set_delegate(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    delegate: Pubkey,
+    permissions: u8,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let staking_account = next_account_info(accounts_iter)?;
+    let user_account = next_account_info(accounts_iter)?;
+
+    // Ensure that the staking account belongs to this program
+    if staking_account.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // Deserialize staking account
+    let mut staking_data = load_position(staking_account)?;
+
+    if !staking_data.is_initialized.get() {
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    if !user_account.is_signer || staking_data.user != *user_account.key {
+        msg!("Only the staking account owner can set a delegate");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    // The delegate would otherwise outlive a transfer of the position token
+    if staking_data.position_mint != Pubkey::default() {
+        msg!("Positions held as tokens cannot have a delegate");
+        return Err(StakingError::Unauthorized.into());
+    }
+
+    if permissions & !(DELEGATE_CLAIM | DELEGATE_COMPOUND | DELEGATE_RESTAKE) != 0 {
+        msg!("Unknown delegate permission bits {:#04x}", permissions);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    staking_data.delegate = delegate;
+    staking_data.delegate_permissions = permissions;
+
+    msg!(
+        "Set delegate {} with permissions {:#04x}",
+        delegate,
+        permissions
+    );
+    Ok(())
+}
+
+fn revoke_delegate(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let staking_account = next_account_info(accounts_iter)?;
+    let user_account = next_account_info(accounts_iter)?;
+
+    // Ensure that the staking account belongs to this program
+    if staking_account.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // Deserialize staking account
+    let mut staking_data = load_position(staking_account)?;
+
+    if !staking_data.is_initialized.get() {
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    if !user_account.is_signer || staking_data.user != *user_account.key {
+        msg!("Only the staking account owner can revoke a delegate");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    staking_data.delegate = Pubkey::default();
+    staking_data.delegate_permissions = 0;
+
+    msg!("Revoked delegate");
+    Ok(())
+}
+
+fn compound_rewards(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let staking_account = next_account_info(accounts_iter)?;
+    let signer_account = next_account_info(accounts_iter)?;
+    let user_positions = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    let sysvar_clock = Clock::get()?;
+    let clock = accrual_clock(&pool_data, &sysvar_clock);
+    let active_from = activation_clock(&pool_data, &sysvar_clock)?;
+    if pool_data.is_paused.get() {
+        msg!("Pool is paused, compounding is disabled");
+        return Err(StakingError::PoolPaused.into());
+    }
+    check_legacy_pool(&pool_data)?;
+
+    // Ensure that the staking account belongs to this program
+    if staking_account.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // Deserialize staking account
+    let mut staking_data = load_position(staking_account)?;
+
+    if !staking_data.is_initialized.get() {
+        return Err(ProgramError::UninitializedAccount);
+    }
+    check_position_pool(&staking_data, pool_account)?;
+
+    check_position_signer(
+        &staking_data,
+        signer_account,
+        accounts_iter,
+        DELEGATE_COMPOUND,
+    )?;
+    refresh_boost(&mut staking_data, accounts_iter)?;
+
+    if clock.saturating_sub(staking_data.lock_start) < min_duration(&pool_data, &staking_data) {
+        msg!("Minimum staking duration not reached, nothing to compound");
+        return Ok(());
+    }
+    if clock < staking_data.stake_start_time {
+        msg!("Latest deposit is still warming up, nothing to compound");
+        return Ok(());
+    }
+
+    // Rewards are paid in the staked token and already sit in the vault
+    update_staked_rewards(&mut pool_data, clock)?;
+    settle_staked_rewards(&pool_data, &mut staking_data)?;
+    let rewards = std::mem::take(&mut staking_data.staked_rewards_owed);
+    let referrer = staking_data.referrer;
+    drop(staking_data);
+
+    // The protocol fee leaves the vault, so it needs the vault, token program and the
+    // pool authority
+    let (rewards, reward_fee) = if pool_data.reward_fee_bps > 0 {
+        let token_account = next_account_info(accounts_iter)?;
+        let token_program = next_account_info(accounts_iter)?;
+        let vault_signer = next_vault_signer(
+            program_id,
+            pool_account,
+            &pool_data,
+            token_account,
+            staking_account,
+            accounts_iter,
+        )?;
+        let treasury = next_treasury(&pool_data, accounts_iter, true)?;
+        take_reward_fee(
+            &mut pool_data,
+            treasury,
+            &vault_signer,
+            token_account,
+            token_program,
+            rewards,
+        )?
+    } else {
+        (rewards, 0)
+    };
+    credit_referrer(
+        program_id,
+        pool_account,
+        &pool_data,
+        &referrer,
+        accounts_iter,
+        rewards,
+    )?;
+
+    let mut staking_data = load_position(staking_account)?;
+    let new_total_staked = pool_data
+        .total_staked
+        .checked_add(rewards)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    if pool_data.max_total_staked != 0 && new_total_staked > pool_data.max_total_staked {
+        msg!("Compounding would exceed the pool capacity");
+        return Err(StakingError::PoolCapacityExceeded.into());
+    }
+
+    let mut positions_data = load_user_positions(
+        program_id,
+        pool_account,
+        user_positions,
+        &staking_data.user,
+        None,
+    )?;
+    let new_user_staked = positions_data
+        .amount_staked
+        .checked_add(rewards)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    if pool_data.max_per_user != 0 && new_user_staked > pool_data.max_per_user {
+        msg!("Compounding would exceed the per-user limit");
+        return Err(StakingError::UserStakeLimitExceeded.into());
+    }
+
+    // Settle stream rewards at the old balance before it changes
+    update_reward_streams(&mut pool_data, clock);
+    settle_reward_streams(&pool_data, &mut staking_data)?;
+
+    // Compounded rewards warm up like any other deposit
+    add_to_position(&pool_data, &mut staking_data, rewards, active_from)?;
+    staking_data.stake_start_time = staking_data.stake_start_time.max(active_from);
+    pool_data.total_staked = new_total_staked;
+    positions_data.deposit(&staking_data, rewards, active_from)?;
+    if pool_data.accrual_mode == ACCRUAL_EPOCH && active_from == clock {
+        reprice_reward_streams(&mut pool_data);
+    }
+    pool_data.stats.total_rewards_paid = pool_data.stats.total_rewards_paid.saturating_add(rewards);
+    record_checkpoints(&mut pool_data, &mut staking_data)?;
+
+    // The pool and position are written in place, only the running total is saved
+    positions_data.serialize(&mut &mut user_positions.data.borrow_mut()[..])?;
+
+    msg!(
+        "Compounded {} rewards into the position ({} fee)",
+        rewards,
+        reward_fee
+    );
+    Ok(())
+}
+
+fn initialize_stake(program_id: &Pubkey, accounts: &[AccountInfo], nft: bool) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let staking_account = next_account_info(accounts_iter)?;
+    let user_account = next_account_info(accounts_iter)?;
+
+    let pool_data = load_pool(program_id, pool_account)?;
+    let clock = accrual_clock(&pool_data, &Clock::get()?);
+
+    // Ensure that the staking account belongs to this program
+    if staking_account.owner != program_id {
+        msg!("Staking account does not have the correct program ID");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if !user_account.is_signer {
+        msg!("Owner signature is required");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    // Legacy pools take no deposits, so there is nothing to open a position for
+    check_legacy_pool(&pool_data)?;
+
+    let mut staking_ref = staking_account.try_borrow_mut_data()?;
+    let staking_data = StakingAccount::load_uninitialized(&mut staking_ref).inspect_err(|err| {
+        if *err == ProgramError::AccountAlreadyInitialized {
+            msg!("Staking account is already initialized");
+        } else {
+            msg!(
+                "Staking account must be exactly {} bytes",
+                StakingAccount::LEN
+            );
+        }
+    })?;
+
+    *staking_data = StakingAccount::new(*user_account.key, *pool_account.key, clock);
+
+    if nft {
+        let position_mint = next_account_info(accounts_iter)?;
+        let position_token_account = next_account_info(accounts_iter)?;
+        let pool_authority = next_account_info(accounts_iter)?;
+        let token_program = next_account_info(accounts_iter)?;
+
+        let (authority_key, authority_bump) = find_pool_authority(program_id, pool_account.key);
+        if *pool_authority.key != authority_key {
+            msg!("Pool authority does not match the derived address");
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        // A fresh zero-decimal mint the pool can mint exactly one token from
+        if position_mint.owner != &spl_token::id() {
+            msg!("Position mint is not a token mint");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let mint_data = Mint::unpack(&position_mint.data.borrow())?;
+        if mint_data.mint_authority != Some(authority_key).into()
+            || mint_data.supply != 0
+            || mint_data.decimals != 0
+        {
+            msg!(
+                "Position mint must be empty, have 0 decimals and be minted by the pool authority"
+            );
+            return Err(StakingError::InvalidPositionMint.into());
+        }
+
+        let authority_seeds: &[&[u8]] = &[
+            POOL_AUTHORITY_SEED,
+            pool_account.key.as_ref(),
+            &[authority_bump],
+        ];
+
+        let mint_ix = mint_to(
+            token_program.key,
+            position_mint.key,
+            position_token_account.key,
+            pool_authority.key,
+            &[],
+            1,
+        )?;
+        invoke_signed(
+            &mint_ix,
+            &[
+                position_mint.clone(),
+                position_token_account.clone(),
+                pool_authority.clone(),
+                token_program.clone(),
+            ],
+            &[authority_seeds],
+        )?;
+
+        // Drop the mint authority so the supply stays at exactly one
+        let set_authority_ix = set_authority(
+            token_program.key,
+            position_mint.key,
+            None,
+            AuthorityType::MintTokens,
+            pool_authority.key,
+            &[],
+        )?;
+        invoke_signed(
+            &set_authority_ix,
+            &[
+                position_mint.clone(),
+                pool_authority.clone(),
+                token_program.clone(),
+            ],
+            &[authority_seeds],
+        )?;
+
+        staking_data.position_mint = *position_mint.key;
+        msg!("Minted position token {}", position_mint.key);
+    }
+
+    msg!("Initialized staking account for {}", user_account.key);
+    Ok(())
+}
+
+fn release_vested(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let vesting_account = next_account_info(accounts_iter)?;
+    let user_account = next_account_info(accounts_iter)?;
+    let destination = next_account_info(accounts_iter)?;
+    let vesting_vault = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let pool_authority = next_account_info(accounts_iter)?;
+
+    let pool_data = load_pool(program_id, pool_account)?;
+    let clock = accrual_clock(&pool_data, &Clock::get()?);
+    if pool_data.is_paused.get() {
+        msg!("Pool is paused, vested reward releases are disabled");
+        return Err(StakingError::PoolPaused.into());
+    }
+
+    if vesting_account.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut vesting_data = VestingAccount::try_from_slice(&vesting_account.data.borrow())?;
+
+    if !vesting_data.is_initialized {
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    if !user_account.is_signer || vesting_data.owner != *user_account.key {
+        msg!("Only the vesting account owner can release rewards");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if vesting_data.pool != *pool_account.key || *vesting_vault.key != pool_data.vesting_vault {
+        msg!("Vesting account or vault does not match the pool");
+        return Err(StakingError::InvalidVestingAccount.into());
+    }
+
+    let (authority_key, authority_bump) = find_pool_authority(program_id, pool_account.key);
+    if *pool_authority.key != authority_key {
+        msg!("Pool authority does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    vesting_data.unlock(clock);
+    let released = vesting_data.unlocked_amount;
+
+    if released > 0 {
+        let transfer_ix = transfer(
+            token_program.key,
+            vesting_vault.key,
+            destination.key,
+            pool_authority.key,
+            &[],
+            released,
+        )?;
+        invoke_signed(
+            &transfer_ix,
+            &[
+                vesting_vault.clone(),
+                destination.clone(),
+                pool_authority.clone(),
+                token_program.clone(),
+            ],
+            &[&[
+                POOL_AUTHORITY_SEED,
+                pool_account.key.as_ref(),
+                &[authority_bump],
+            ]],
+        )?;
+        vesting_data.unlocked_amount = 0;
+    }
+
+    vesting_data.serialize(&mut &mut vesting_account.data.borrow_mut()[..])?;
+
+    msg!(
+        "Released {} vested rewards, {} still locked",
+        released,
+        vesting_data.locked_amount
+    );
+    Ok(())
+}
+
+fn claim_referral_rewards(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let referral_account = next_account_info(accounts_iter)?;
+    let referrer_account = next_account_info(accounts_iter)?;
+    let destination = next_account_info(accounts_iter)?;
+    let referral_vault = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let pool_authority = next_account_info(accounts_iter)?;
+
+    let pool_data = load_pool(program_id, pool_account)?;
+    if pool_data.is_paused.get() {
+        msg!("Pool is paused, reward claims are disabled");
+        return Err(StakingError::PoolPaused.into());
+    }
+
+    if referral_account.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut referral_data = ReferralAccount::try_from_slice(&referral_account.data.borrow())?;
+
+    if !referral_data.is_initialized {
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    if !referrer_account.is_signer || referral_data.referrer != *referrer_account.key {
+        msg!("Only the referrer can claim referral rewards");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if referral_data.pool != *pool_account.key || *referral_vault.key != pool_data.referral_vault {
+        msg!("Referral account or vault does not match the pool");
+        return Err(StakingError::InvalidReferralAccount.into());
+    }
+
+    let (authority_key, authority_bump) = find_pool_authority(program_id, pool_account.key);
+    if *pool_authority.key != authority_key {
+        msg!("Pool authority does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    let claimable = referral_data.claimable;
+    if claimable > 0 {
+        let transfer_ix = transfer(
+            token_program.key,
+            referral_vault.key,
+            destination.key,
+            pool_authority.key,
+            &[],
+            claimable,
+        )?;
+        invoke_signed(
+            &transfer_ix,
+            &[
+                referral_vault.clone(),
+                destination.clone(),
+                pool_authority.clone(),
+                token_program.clone(),
+            ],
+            &[&[
+                POOL_AUTHORITY_SEED,
+                pool_account.key.as_ref(),
+                &[authority_bump],
+            ]],
+        )?;
+        referral_data.claimable = 0;
+    }
+
+    referral_data.serialize(&mut &mut referral_account.data.borrow_mut()[..])?;
+
+    msg!("Claimed {} referral rewards", claimable);
+    Ok(())
+}
+
+fn apply_boost(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let staking_account = next_account_info(accounts_iter)?;
+    let user_account = next_account_info(accounts_iter)?;
+    let nft_token_account = next_account_info(accounts_iter)?;
+    let nft_metadata = next_account_info(accounts_iter)?;
+    let boost_record = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    let mut pool_data = load_pool(program_id, pool_account)?;
+    let clock = accrual_clock(&pool_data, &Clock::get()?);
+    if pool_data.boost_multiplier_bps == 0 {
+        msg!("Pool does not offer an NFT boost");
+        return Err(StakingError::InvalidBoostNft.into());
+    }
+
+    // Ensure that the staking account belongs to this program
+    if staking_account.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // Deserialize staking account
+    let mut staking_data = load_position(staking_account)?;
+
+    if !staking_data.is_initialized.get() {
+        return Err(ProgramError::UninitializedAccount);
+    }
+    check_position_pool(&staking_data, pool_account)?;
+
+    check_position_owner(&staking_data, user_account, accounts_iter)?;
+
+    // The position owner must hold exactly one token of the NFT mint
+    if nft_token_account.owner != &spl_token::id() {
+        msg!("NFT token account is not a token account");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let holder_data = TokenAccount::unpack(&nft_token_account.data.borrow())?;
+    if holder_data.owner != staking_data.user || holder_data.amount != 1 {
+        msg!("Position owner does not hold the boost NFT");
+        return Err(StakingError::InvalidBoostNft.into());
+    }
+
+    // The metadata must be the canonical account for the mint and sit in the verified collection
+    let (metadata_key, _) = Pubkey::find_program_address(
+        &[
+            b"metadata",
+            TOKEN_METADATA_PROGRAM_ID.as_ref(),
+            holder_data.mint.as_ref(),
+        ],
+        &TOKEN_METADATA_PROGRAM_ID,
+    );
+    if *nft_metadata.key != metadata_key || nft_metadata.owner != &TOKEN_METADATA_PROGRAM_ID {
+        msg!("NFT metadata account does not match the mint");
+        return Err(StakingError::InvalidBoostNft.into());
+    }
+    let metadata =
+        MetadataPrefix::deserialize(&mut &nft_metadata.data.borrow()[..]).map_err(|_| {
+            msg!("Failed to deserialize NFT metadata");
+            StakingError::InvalidBoostNft
+        })?;
+    let in_collection = metadata.mint == holder_data.mint
+        && metadata
+            .collection
+            .map(|collection| collection.verified && collection.key == pool_data.boost_collection)
+            .unwrap_or(false);
+    if !in_collection {
+        msg!("NFT is not a verified member of the pool's boost collection");
+        return Err(StakingError::InvalidBoostNft.into());
+    }
+
+    let (record_key, record_bump) =
+        find_boost_record(program_id, pool_account.key, &holder_data.mint);
+    if *boost_record.key != record_key {
+        msg!("Boost record does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    // An NFT boosts one position at a time, applying it elsewhere moves it here
+    let previous_position = if boost_record.data_is_empty() {
+        let record_len = BoostRecord {
+            pool: *pool_account.key,
+            mint: holder_data.mint,
+            position: *staking_account.key,
+            is_initialized: true,
+        }
+        .try_to_vec()?
+        .len();
+        invoke_signed(
+            &system_instruction::create_account(
+                user_account.key,
+                boost_record.key,
+                Rent::get()?.minimum_balance(record_len),
+                record_len as u64,
+                program_id,
+            ),
+            &[
+                user_account.clone(),
+                boost_record.clone(),
+                system_program.clone(),
+            ],
+            &[&[
+                BOOST_RECORD_SEED,
+                pool_account.key.as_ref(),
+                holder_data.mint.as_ref(),
+                &[record_bump],
+            ]],
+        )?;
+        None
+    } else {
+        if boost_record.owner != program_id {
+            msg!("Boost record does not have the correct program ID");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let record_data = BoostRecord::try_from_slice(&boost_record.data.borrow())?;
+        Some(record_data.position).filter(|position| position != staking_account.key)
+    };
+
+    if let Some(previous_position) = previous_position {
+        let previous_account = next_account_info(accounts_iter)?;
+        if *previous_account.key != previous_position {
+            msg!(
+                "Previously boosted position {} is required",
+                previous_position
+            );
+            return Err(StakingError::InvalidBoostNft.into());
+        }
+        // A closed position has no boost left to remove
+        let previous_data = if previous_account.owner == program_id {
+            load_position(previous_account).ok()
+        } else {
+            None
+        };
+        if let Some(mut previous_data) =
+            previous_data.filter(|previous_data| previous_data.boost_mint == holder_data.mint)
+        {
+            msg!("Removing boost from position {}", previous_position);
+            update_staked_rewards(&mut pool_data, clock)?;
+            settle_staked_rewards(&pool_data, &mut previous_data)?;
+            previous_data.boost_bps = 0;
+            previous_data.boost_start_time = 0;
+            previous_data.boost_mint = Pubkey::default();
+            previous_data.boost_token_account = Pubkey::default();
+        }
+    }
+
+    BoostRecord {
+        pool: *pool_account.key,
+        mint: holder_data.mint,
+        position: *staking_account.key,
+        is_initialized: true,
+    }
+    .serialize(&mut &mut boost_record.data.borrow_mut()[..])?;
+
+    // Rewards accrued so far are settled at the old multiplier
+    update_staked_rewards(&mut pool_data, clock)?;
+    settle_staked_rewards(&pool_data, &mut staking_data)?;
+    staking_data.boost_bps = pool_data.boost_multiplier_bps;
+    staking_data.boost_start_time = clock;
+    staking_data.boost_mint = holder_data.mint;
+    staking_data.boost_token_account = *nft_token_account.key;
+
+    msg!(
+        "Applied {} bps boost from NFT {}",
+        staking_data.boost_bps,
+        holder_data.mint
+    );
+    Ok(())
+}
+
+fn update_voter_weight_record(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    realm: Pubkey,
+    governing_token_mint: Pubkey,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let user_positions = next_account_info(accounts_iter)?;
+    let voter_weight_record = next_account_info(accounts_iter)?;
+    let user_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let clock = Clock::get()?;
+
+    let pool_data = load_pool(program_id, pool_account)?;
+
+    if pool_data.voter_weight_realm == Pubkey::default()
+        || realm != pool_data.voter_weight_realm
+        || governing_token_mint != pool_data.voter_weight_mint
+    {
+        msg!("Voter weight records are not issued for this realm and mint");
+        return Err(StakingError::InvalidVoterWeightRecord.into());
+    }
+
+    // Stakes in legacy pools were never proven to reach a vault
+    check_legacy_pool(&pool_data)?;
+
+    if !user_account.is_signer {
+        msg!("Voter signature is required");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    // The weight covers everything the voter has staked in the pool. Positions held as
+    // tokens are left out: records expire at the end of the slot, not when a token
+    // changes hands, so each holder could otherwise vote with it in the same slot.
+    let positions_data = load_user_positions(
+        program_id,
+        pool_account,
+        user_positions,
+        user_account.key,
+        None,
+    )?;
+
+    let (record_key, record_bump) =
+        find_voter_weight_record(program_id, &realm, &governing_token_mint, user_account.key);
+    if *voter_weight_record.key != record_key {
+        msg!("Voter weight record does not match the derived address");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    // The record is created on the voter's first update, an existing one must match it
+    if voter_weight_record.data_is_empty() {
+        invoke_signed(
+            &system_instruction::create_account(
+                user_account.key,
+                voter_weight_record.key,
+                Rent::get()?.minimum_balance(VOTER_WEIGHT_RECORD_LEN),
+                VOTER_WEIGHT_RECORD_LEN as u64,
+                program_id,
+            ),
+            &[
+                user_account.clone(),
+                voter_weight_record.clone(),
+                system_program.clone(),
+            ],
+            &[&[
+                VOTER_WEIGHT_RECORD_SEED,
+                realm.as_ref(),
+                governing_token_mint.as_ref(),
+                user_account.key.as_ref(),
+                &[record_bump],
+            ]],
+        )?;
+    } else {
+        if voter_weight_record.owner != program_id {
+            msg!("Voter weight record does not have the correct program ID");
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let existing = VoterWeightRecord::deserialize(&mut &voter_weight_record.data.borrow()[..])
+            .map_err(|_| {
+                msg!("Failed to deserialize voter weight record");
+                ProgramError::InvalidAccountData
+            })?;
+        if existing.account_discriminator != VOTER_WEIGHT_RECORD_DISCRIMINATOR
+            || existing.realm != realm
+            || existing.governing_token_mint != governing_token_mint
+            || existing.governing_token_owner != *user_account.key
+        {
+            msg!("Voter weight record belongs to another realm, mint or voter");
+            return Err(StakingError::InvalidVoterWeightRecord.into());
+        }
+    }
+
+    // The weight is only valid for the slot it was computed in
+    let record = VoterWeightRecord {
+        account_discriminator: VOTER_WEIGHT_RECORD_DISCRIMINATOR,
+        realm,
+        governing_token_mint,
+        governing_token_owner: *user_account.key,
+        voter_weight: calculate_voter_weight(
+            &pool_data,
+            &positions_data,
+            accrual_clock(&pool_data, &clock),
+        )?,
+        voter_weight_expiry: Some(clock.slot),
+        weight_action: None,
+        weight_action_target: None,
+        reserved: [0; 8],
+    };
+    record.serialize(&mut &mut voter_weight_record.data.borrow_mut()[..])?;
+
+    msg!(
+        "Voter weight for {} set to {} until slot {}",
+        user_account.key,
+        record.voter_weight,
+        clock.slot
+    );
+    Ok(())
+}
+
+fn get_stake_at(program_id: &Pubkey, accounts: &[AccountInfo], timestamp: u64) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+
+    let pool_data = load_pool(program_id, pool_account)?;
+
+    // With a staking account the position's balance is returned, otherwise the pool total
+    let history = match accounts_iter.next() {
+        Some(staking_account) => {
+            if staking_account.owner != program_id {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+            let staking_ref = staking_account.data.borrow();
+            let staking_data = StakingAccount::load(&staking_ref)?;
+            if !staking_data.is_initialized.get() {
+                return Err(ProgramError::UninitializedAccount);
+            }
+            check_position_pool(staking_data, pool_account)?;
+            staking_data.checkpoints
+        }
+        None => pool_data.checkpoints,
+    };
+
+    let balance = history.balance_at(timestamp).ok_or_else(|| {
+        msg!("No checkpoint retained for timestamp {}", timestamp);
+        ProgramError::from(StakingError::CheckpointUnavailable)
+    })?;
+
+    set_return_data(&balance.to_le_bytes());
+
+    msg!("Staked balance at {}: {}", timestamp, balance);
+    Ok(())
+}
+
+fn get_pending_rewards(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let pool_account = next_account_info(accounts_iter)?;
+    let staking_account = next_account_info(accounts_iter)?;
+
+    // Both copies are only advanced in memory and never written back
+    let mut pool_data = *load_pool(program_id, pool_account)?;
+    let clock = accrual_clock(&pool_data, &Clock::get()?);
+
+    // Ensure that the staking account belongs to this program
+    if staking_account.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // Copy the staking account out
+    let mut staking_data = *StakingAccount::load(&staking_account.data.borrow())?;
+
+    if !staking_data.is_initialized.get() {
+        return Err(ProgramError::UninitializedAccount);
+    }
+    check_position_pool(&staking_data, pool_account)?;
+
+    update_staked_rewards(&mut pool_data, clock)?;
+    settle_staked_rewards(&pool_data, &mut staking_data)?;
+
+    let min_duration_reached =
+        clock.saturating_sub(staking_data.lock_start) >= min_duration(&pool_data, &staking_data);
+    let rewards = if min_duration_reached {
+        staking_data.staked_rewards_owed
+    } else {
+        0
+    };
+    let reward_fee = fee_for(rewards, pool_data.reward_fee_bps);
+    let rewards = rewards - reward_fee;
+
+    update_reward_streams(&mut pool_data, clock);
+    settle_reward_streams(&pool_data, &mut staking_data)?;
+
+    let summary = PendingRewards {
+        timestamp: clock,
+        amount_staked: staking_data.amount_staked,
+        rewards,
+        min_duration_reached,
+        stream_rewards: staking_data.rewards_owed,
+        apr_bps: current_apr_bps(&pool_data, clock)?,
+    };
+    set_return_data(&summary.try_to_vec()?);
+
+    msg!("Pending rewards: {:?}", summary);
+    Ok(())
+}
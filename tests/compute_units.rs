@@ -0,0 +1,924 @@
+// Compute units used by Stake, ClaimRewards and Unstake on a created pool, plus the
+// pause, fee and cap paths around them.
+//
+// `cargo test-sbf -- --nocapture` runs the deployed program, prints the units each
+// instruction consumes and holds them to the budgets below. Plain `cargo test` runs the
+// native processor, which still checks the flow but only meters the token program CPIs,
+// so the budgets then only catch extra CPIs.
+//
+// The budgets are ceilings with headroom for the in-place account access, not
+// measurements. Tighten them from the numbers `cargo test-sbf` prints, and compare
+// against a run at the commit before pool and position state were accessed in place
+// for the cost of the old copy-out/copy-back.
+
+use solana_program_test::{
+    processor, BanksClient, ProgramTest, ProgramTestBanksClientExt, ProgramTestContext,
+};
+use solana_sdk::{
+    account::Account,
+    clock::Clock,
+    instruction::{AccountMeta, Instruction, InstructionError},
+    program_pack::Pack,
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+    system_instruction, system_program,
+    transaction::{Transaction, TransactionError},
+};
+use spl_token::state::{Account as TokenAccount, Mint};
+use staking_token::{
+    find_pool_address, find_pool_authority, find_pool_vault, find_position_address,
+    find_user_positions, find_voter_weight_record, process_instruction, StakingAccount,
+    StakingError, StakingPool,
+};
+
+const SEED: u64 = 7;
+const STAKE_AMOUNT: u64 = 1_000_000_000;
+const REWARD_FUNDING: u64 = 1_000_000_000;
+const APR_BPS: u32 = 1_000;
+const ONE_DAY: i64 = 86_400;
+
+// Compute unit budgets per instruction
+const STAKE_CU_BUDGET: u64 = 50_000;
+const CLAIM_CU_BUDGET: u64 = 50_000;
+const UNSTAKE_CU_BUDGET: u64 = 50_000;
+
+struct Setup {
+    context: ProgramTestContext,
+    program_id: Pubkey,
+    mint: Pubkey,
+    user: Keypair,
+    user_token: Pubkey,
+    pool: Pubkey,
+    vault: Pubkey,
+    authority: Pubkey,
+    user_positions: Pubkey,
+    position: Pubkey,
+}
+
+// Send `instructions` signed by the payer and `signers`, returning the compute units used
+async fn send(
+    context: &mut ProgramTestContext,
+    instructions: &[Instruction],
+    signers: &[&Keypair],
+) -> u64 {
+    try_send(context, instructions, signers)
+        .await
+        .unwrap_or_else(|(err, logs)| panic!("transaction failed: {err:?}\n{logs:#?}"))
+}
+
+// Like `send`, but hand a failed transaction's error and logs back to the caller
+async fn try_send(
+    context: &mut ProgramTestContext,
+    instructions: &[Instruction],
+    signers: &[&Keypair],
+) -> Result<u64, (TransactionError, Vec<String>)> {
+    let blockhash = context
+        .banks_client
+        .get_new_latest_blockhash(&context.last_blockhash)
+        .await
+        .unwrap();
+    context.last_blockhash = blockhash;
+
+    let mut all_signers = vec![&context.payer];
+    all_signers.extend_from_slice(signers);
+    let transaction = Transaction::new_signed_with_payer(
+        instructions,
+        Some(&context.payer.pubkey()),
+        &all_signers,
+        blockhash,
+    );
+    let result = context
+        .banks_client
+        .process_transaction_with_metadata(transaction)
+        .await
+        .unwrap();
+    let metadata = result.metadata.unwrap();
+    match result.result {
+        Ok(()) => Ok(metadata.compute_units_consumed),
+        Err(err) => Err((err, metadata.log_messages)),
+    }
+}
+
+async fn token_balance(banks_client: &mut BanksClient, address: Pubkey) -> u64 {
+    let account = banks_client.get_account(address).await.unwrap().unwrap();
+    TokenAccount::unpack(&account.data).unwrap().amount
+}
+
+// Move the clock forward by `seconds` on a fresh slot
+async fn advance_clock(context: &mut ProgramTestContext, seconds: i64) {
+    let mut clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+    context.warp_to_slot(clock.slot + 100).unwrap();
+    clock = context.banks_client.get_sysvar().await.unwrap();
+    clock.unix_timestamp += seconds;
+    context.set_sysvar(&clock);
+}
+
+fn create_token_account(
+    payer: &Pubkey,
+    account: &Pubkey,
+    mint: &Pubkey,
+    owner: &Pubkey,
+    lamports: u64,
+) -> Vec<Instruction> {
+    vec![
+        system_instruction::create_account(
+            payer,
+            account,
+            lamports,
+            TokenAccount::LEN as u64,
+            &spl_token::id(),
+        ),
+        spl_token::instruction::initialize_account3(&spl_token::id(), account, mint, owner)
+            .unwrap(),
+    ]
+}
+
+// Stake `amount` from `user_token` into `position`, owned by `user`
+fn stake(
+    program_id: &Pubkey,
+    pool: &Pubkey,
+    position: &Pubkey,
+    user: &Pubkey,
+    user_token: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let (vault, _) = find_pool_vault(program_id, pool);
+    let (user_positions, _) = find_user_positions(program_id, pool, user);
+    let mut data = vec![0];
+    data.extend_from_slice(&amount.to_le_bytes());
+    Instruction::new_with_bytes(
+        *program_id,
+        &data,
+        vec![
+            AccountMeta::new(*pool, false),
+            AccountMeta::new(*position, false),
+            AccountMeta::new(*user, true),
+            AccountMeta::new(*user_token, false),
+            AccountMeta::new(vault, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new(user_positions, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+// Unstake everything in `position` back to `user_token`, with any `extra` accounts
+fn unstake(
+    program_id: &Pubkey,
+    pool: &Pubkey,
+    position: &Pubkey,
+    user: &Pubkey,
+    user_token: &Pubkey,
+    extra: &[AccountMeta],
+) -> Instruction {
+    let (vault, _) = find_pool_vault(program_id, pool);
+    let (authority, _) = find_pool_authority(program_id, pool);
+    let (user_positions, _) = find_user_positions(program_id, pool, user);
+    let mut accounts = vec![
+        AccountMeta::new(*pool, false),
+        AccountMeta::new(*position, false),
+        AccountMeta::new(*user, true),
+        AccountMeta::new(*user_token, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new(user_positions, false),
+        AccountMeta::new_readonly(authority, false),
+    ];
+    accounts.extend_from_slice(extra);
+    Instruction::new_with_bytes(*program_id, &[1], accounts)
+}
+
+// OpenPosition number `index` for `user` with no lock
+fn open_position(program_id: &Pubkey, pool: &Pubkey, user: &Pubkey, index: u64) -> Instruction {
+    let (user_positions, _) = find_user_positions(program_id, pool, user);
+    let (position, _) = find_position_address(program_id, pool, user, index);
+    let mut data = vec![34];
+    data.extend_from_slice(&0u64.to_le_bytes());
+    Instruction::new_with_bytes(
+        *program_id,
+        &data,
+        vec![
+            AccountMeta::new(*pool, false),
+            AccountMeta::new(user_positions, false),
+            AccountMeta::new(position, false),
+            AccountMeta::new(*user, true),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+// CreatePool for `mint` and `seed` with `admin` as the admin and payer
+fn create_pool(program_id: &Pubkey, admin: &Pubkey, mint: &Pubkey, seed: u64) -> Instruction {
+    let (pool, _) = find_pool_address(program_id, mint, seed);
+    let (vault, _) = find_pool_vault(program_id, &pool);
+    let (authority, _) = find_pool_authority(program_id, &pool);
+    let mut data = vec![33];
+    data.extend_from_slice(&seed.to_le_bytes());
+    Instruction::new_with_bytes(
+        *program_id,
+        &data,
+        vec![
+            AccountMeta::new(pool, false),
+            AccountMeta::new(*admin, true),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new(vault, false),
+            AccountMeta::new_readonly(authority, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+// Create a mint, a pool with an APR, a funded user and an empty position
+async fn setup() -> Setup {
+    let program_id = Pubkey::new_unique();
+    let mut program_test =
+        ProgramTest::new("staking_token", program_id, processor!(process_instruction));
+    let user = Keypair::new();
+    program_test.add_account(
+        user.pubkey(),
+        Account {
+            lamports: 10_000_000_000,
+            owner: system_program::id(),
+            ..Account::default()
+        },
+    );
+    let mut context = program_test.start_with_context().await;
+    let payer = context.payer.pubkey();
+    let rent = context.banks_client.get_rent().await.unwrap();
+
+    let mint = Keypair::new();
+    let user_token = Keypair::new();
+    let mut instructions = vec![
+        system_instruction::create_account(
+            &payer,
+            &mint.pubkey(),
+            rent.minimum_balance(Mint::LEN),
+            Mint::LEN as u64,
+            &spl_token::id(),
+        ),
+        spl_token::instruction::initialize_mint2(&spl_token::id(), &mint.pubkey(), &payer, None, 9)
+            .unwrap(),
+    ];
+    instructions.extend(create_token_account(
+        &payer,
+        &user_token.pubkey(),
+        &mint.pubkey(),
+        &user.pubkey(),
+        rent.minimum_balance(TokenAccount::LEN),
+    ));
+    instructions.push(
+        spl_token::instruction::mint_to(
+            &spl_token::id(),
+            &mint.pubkey(),
+            &user_token.pubkey(),
+            &payer,
+            &[],
+            STAKE_AMOUNT,
+        )
+        .unwrap(),
+    );
+    send(&mut context, &instructions, &[&mint, &user_token]).await;
+
+    let (pool, _) = find_pool_address(&program_id, &mint.pubkey(), SEED);
+    let (vault, _) = find_pool_vault(&program_id, &pool);
+    let (authority, _) = find_pool_authority(&program_id, &pool);
+    let mut set_apr_data = vec![32];
+    set_apr_data.extend_from_slice(&APR_BPS.to_le_bytes());
+    let instructions = [
+        create_pool(&program_id, &payer, &mint.pubkey(), SEED),
+        Instruction::new_with_bytes(
+            program_id,
+            &set_apr_data,
+            vec![AccountMeta::new(pool, false), AccountMeta::new(payer, true)],
+        ),
+        // Rewards in the staked token are paid out of the principal vault
+        spl_token::instruction::mint_to(
+            &spl_token::id(),
+            &mint.pubkey(),
+            &vault,
+            &payer,
+            &[],
+            REWARD_FUNDING,
+        )
+        .unwrap(),
+    ];
+    send(&mut context, &instructions, &[]).await;
+
+    let (user_positions, _) = find_user_positions(&program_id, &pool, &user.pubkey());
+    let (position, _) = find_position_address(&program_id, &pool, &user.pubkey(), 0);
+    send(
+        &mut context,
+        &[open_position(&program_id, &pool, &user.pubkey(), 0)],
+        &[&user],
+    )
+    .await;
+
+    Setup {
+        context,
+        program_id,
+        mint: mint.pubkey(),
+        user,
+        user_token: user_token.pubkey(),
+        pool,
+        vault,
+        authority,
+        user_positions,
+        position,
+    }
+}
+
+#[tokio::test]
+async fn stake_claim_unstake_compute_units() {
+    let Setup {
+        mut context,
+        program_id,
+        user,
+        user_token,
+        pool,
+        vault,
+        authority,
+        position,
+        ..
+    } = setup().await;
+
+    let stake = stake(
+        &program_id,
+        &pool,
+        &position,
+        &user.pubkey(),
+        &user_token,
+        STAKE_AMOUNT,
+    );
+    let stake_units = send(&mut context, &[stake], &[&user]).await;
+    assert_eq!(
+        token_balance(&mut context.banks_client, vault).await,
+        REWARD_FUNDING + STAKE_AMOUNT
+    );
+
+    advance_clock(&mut context, ONE_DAY).await;
+    let claim = Instruction::new_with_bytes(
+        program_id,
+        &[9],
+        vec![
+            AccountMeta::new(pool, false),
+            AccountMeta::new(position, false),
+            AccountMeta::new_readonly(user.pubkey(), true),
+            AccountMeta::new(user_token, false),
+            AccountMeta::new(vault, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(authority, false),
+        ],
+    );
+    let claim_units = send(&mut context, &[claim], &[&user]).await;
+    let claimed = token_balance(&mut context.banks_client, user_token).await;
+    // One day at 10% APR, give or take the rounding of the accumulator
+    let expected = STAKE_AMOUNT * APR_BPS as u64 / 10_000 / 365;
+    assert!(claimed.abs_diff(expected) <= 1, "claimed {claimed}");
+
+    advance_clock(&mut context, ONE_DAY).await;
+    let unstake = unstake(
+        &program_id,
+        &pool,
+        &position,
+        &user.pubkey(),
+        &user_token,
+        &[],
+    );
+    let unstake_units = send(&mut context, &[unstake], &[&user]).await;
+    let withdrawn = token_balance(&mut context.banks_client, user_token).await;
+    assert!(withdrawn >= STAKE_AMOUNT + 2 * expected - 2);
+
+    let position_account = context
+        .banks_client
+        .get_account(position)
+        .await
+        .unwrap()
+        .unwrap();
+    let position_data = StakingAccount::load(&position_account.data).unwrap();
+    assert_eq!(position_data.amount_staked, 0);
+    assert_eq!(position_data.pool, pool);
+
+    println!("Stake:        {stake_units} CU (budget {STAKE_CU_BUDGET})");
+    println!("ClaimRewards: {claim_units} CU (budget {CLAIM_CU_BUDGET})");
+    println!("Unstake:      {unstake_units} CU (budget {UNSTAKE_CU_BUDGET})");
+    assert!(
+        stake_units <= STAKE_CU_BUDGET,
+        "Stake used {stake_units} CU"
+    );
+    assert!(
+        claim_units <= CLAIM_CU_BUDGET,
+        "ClaimRewards used {claim_units} CU"
+    );
+    assert!(
+        unstake_units <= UNSTAKE_CU_BUDGET,
+        "Unstake used {unstake_units} CU"
+    );
+}
+
+// Assert that `result` failed in instruction `index` with `error`
+fn assert_staking_error(
+    result: Result<u64, (TransactionError, Vec<String>)>,
+    index: u8,
+    error: StakingError,
+) {
+    let (err, logs) = result.expect_err("transaction should fail");
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(index, InstructionError::Custom(error as u32)),
+        "{logs:#?}"
+    );
+}
+
+// Admin instruction `tag` on `pool` with `data`, signed by the payer
+fn admin_instruction(
+    program_id: &Pubkey,
+    pool: &Pubkey,
+    admin: &Pubkey,
+    data: Vec<u8>,
+    extra: &[AccountMeta],
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*pool, false),
+        AccountMeta::new_readonly(*admin, true),
+    ];
+    accounts.extend_from_slice(extra);
+    Instruction::new_with_bytes(*program_id, &data, accounts)
+}
+
+#[tokio::test]
+async fn paused_pool_rejects_stake_and_unstake() {
+    let Setup {
+        mut context,
+        program_id,
+        user,
+        user_token,
+        pool,
+        position,
+        ..
+    } = setup().await;
+    let admin = context.payer.pubkey();
+    let stake_half = stake(
+        &program_id,
+        &pool,
+        &position,
+        &user.pubkey(),
+        &user_token,
+        STAKE_AMOUNT / 2,
+    );
+    send(&mut context, std::slice::from_ref(&stake_half), &[&user]).await;
+
+    let pause = admin_instruction(&program_id, &pool, &admin, vec![3, 1], &[]);
+    send(&mut context, &[pause], &[]).await;
+    let result = try_send(&mut context, std::slice::from_ref(&stake_half), &[&user]).await;
+    assert_staking_error(result, 0, StakingError::PoolPaused);
+    let unstake = unstake(
+        &program_id,
+        &pool,
+        &position,
+        &user.pubkey(),
+        &user_token,
+        &[],
+    );
+    let result = try_send(&mut context, std::slice::from_ref(&unstake), &[&user]).await;
+    assert_staking_error(result, 0, StakingError::PoolPaused);
+
+    // Unpausing lets both through again
+    let unpause = admin_instruction(&program_id, &pool, &admin, vec![3, 0], &[]);
+    send(&mut context, &[unpause, stake_half, unstake], &[&user]).await;
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_token).await,
+        STAKE_AMOUNT
+    );
+}
+
+#[tokio::test]
+async fn deposit_and_withdraw_fees_go_to_the_treasury() {
+    let Setup {
+        mut context,
+        program_id,
+        mint,
+        user,
+        user_token,
+        pool,
+        position,
+        ..
+    } = setup().await;
+    let admin = context.payer.pubkey();
+    let rent = context.banks_client.get_rent().await.unwrap();
+
+    // 1% in, 2% out
+    let treasury = Keypair::new();
+    let mut instructions = create_token_account(
+        &admin,
+        &treasury.pubkey(),
+        &mint,
+        &admin,
+        rent.minimum_balance(TokenAccount::LEN),
+    );
+    let mut fee_data = vec![28];
+    fee_data.extend_from_slice(&100u16.to_le_bytes());
+    fee_data.extend_from_slice(&200u16.to_le_bytes());
+    instructions.push(admin_instruction(
+        &program_id,
+        &pool,
+        &admin,
+        fee_data,
+        &[AccountMeta::new_readonly(treasury.pubkey(), false)],
+    ));
+    send(&mut context, &instructions, &[&treasury]).await;
+
+    let mut stake = stake(
+        &program_id,
+        &pool,
+        &position,
+        &user.pubkey(),
+        &user_token,
+        STAKE_AMOUNT,
+    );
+    stake
+        .accounts
+        .push(AccountMeta::new(treasury.pubkey(), false));
+    send(&mut context, &[stake], &[&user]).await;
+
+    let deposit_fee = STAKE_AMOUNT / 100;
+    let net_amount = STAKE_AMOUNT - deposit_fee;
+    let position_account = context
+        .banks_client
+        .get_account(position)
+        .await
+        .unwrap()
+        .unwrap();
+    let position_data = StakingAccount::load(&position_account.data).unwrap();
+    assert_eq!(position_data.amount_staked, net_amount);
+    assert_eq!(
+        token_balance(&mut context.banks_client, treasury.pubkey()).await,
+        deposit_fee
+    );
+
+    // Without the treasury the withdraw fee can't be paid
+    let without_treasury = unstake(
+        &program_id,
+        &pool,
+        &position,
+        &user.pubkey(),
+        &user_token,
+        &[],
+    );
+    let (err, _) = try_send(&mut context, &[without_treasury], &[&user])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::NotEnoughAccountKeys)
+    );
+
+    let unstake = unstake(
+        &program_id,
+        &pool,
+        &position,
+        &user.pubkey(),
+        &user_token,
+        &[AccountMeta::new(treasury.pubkey(), false)],
+    );
+    send(&mut context, &[unstake], &[&user]).await;
+    let withdraw_fee = net_amount / 50;
+    assert_eq!(
+        token_balance(&mut context.banks_client, treasury.pubkey()).await,
+        deposit_fee + withdraw_fee
+    );
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_token).await,
+        net_amount - withdraw_fee
+    );
+}
+
+#[tokio::test]
+async fn stake_limits_reject_deposits_over_the_caps() {
+    let Setup {
+        mut context,
+        program_id,
+        user,
+        user_token,
+        pool,
+        position,
+        ..
+    } = setup().await;
+    let admin = context.payer.pubkey();
+    let stake_amount = |amount| {
+        stake(
+            &program_id,
+            &pool,
+            &position,
+            &user.pubkey(),
+            &user_token,
+            amount,
+        )
+    };
+
+    // The per-user cap covers the owner's running total
+    let mut limits_data = vec![6];
+    limits_data.extend_from_slice(&0u64.to_le_bytes());
+    limits_data.extend_from_slice(&(STAKE_AMOUNT / 2).to_le_bytes());
+    let per_user = admin_instruction(&program_id, &pool, &admin, limits_data, &[]);
+    send(&mut context, &[per_user], &[]).await;
+    send(&mut context, &[stake_amount(STAKE_AMOUNT / 4)], &[&user]).await;
+    let result = try_send(&mut context, &[stake_amount(STAKE_AMOUNT / 2)], &[&user]).await;
+    assert_staking_error(result, 0, StakingError::UserStakeLimitExceeded);
+
+    // The pool cap covers everyone
+    let mut limits_data = vec![6];
+    limits_data.extend_from_slice(&(STAKE_AMOUNT / 3).to_le_bytes());
+    limits_data.extend_from_slice(&0u64.to_le_bytes());
+    let pool_cap = admin_instruction(&program_id, &pool, &admin, limits_data, &[]);
+    send(&mut context, &[pool_cap], &[]).await;
+    let result = try_send(&mut context, &[stake_amount(STAKE_AMOUNT / 4)], &[&user]).await;
+    assert_staking_error(result, 0, StakingError::PoolCapacityExceeded);
+
+    // Below the minimum is rejected before either cap
+    let mut minimums_data = vec![7];
+    minimums_data.extend_from_slice(&(STAKE_AMOUNT / 10).to_le_bytes());
+    minimums_data.extend_from_slice(&0u64.to_le_bytes());
+    let minimums = admin_instruction(&program_id, &pool, &admin, minimums_data, &[]);
+    send(&mut context, &[minimums], &[]).await;
+    let result = try_send(&mut context, &[stake_amount(STAKE_AMOUNT / 20)], &[&user]).await;
+    assert_staking_error(result, 0, StakingError::BelowMinimumStake);
+}
+
+#[tokio::test]
+async fn pool_is_never_read_as_a_position() {
+    let Setup {
+        mut context,
+        program_id,
+        mint,
+        user,
+        user_token,
+        pool,
+        vault,
+        authority,
+        ..
+    } = setup().await;
+
+    // The attacker opens a pool of their own and tries to initialize the victim's pool
+    // as a position in it, which would overwrite its admin
+    let attacker = context.payer.insecure_clone();
+    let (attacker_pool, _) = find_pool_address(&program_id, &mint, SEED + 1);
+    send(
+        &mut context,
+        &[create_pool(
+            &program_id,
+            &attacker.pubkey(),
+            &mint,
+            SEED + 1,
+        )],
+        &[],
+    )
+    .await;
+    let initialize_stake = Instruction::new_with_bytes(
+        program_id,
+        &[16, 0],
+        vec![
+            AccountMeta::new_readonly(attacker_pool, false),
+            AccountMeta::new(pool, false),
+            AccountMeta::new_readonly(attacker.pubkey(), true),
+        ],
+    );
+    let (err, _) = try_send(&mut context, &[initialize_stake], &[])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::InvalidAccountData)
+    );
+
+    // Nor can the pool stand in for a position it is paying out of
+    let claim = Instruction::new_with_bytes(
+        program_id,
+        &[9],
+        vec![
+            AccountMeta::new(attacker_pool, false),
+            AccountMeta::new(pool, false),
+            AccountMeta::new_readonly(user.pubkey(), true),
+            AccountMeta::new(user_token, false),
+            AccountMeta::new(vault, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(authority, false),
+        ],
+    );
+    let (err, _) = try_send(&mut context, &[claim], &[&user])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(0, InstructionError::InvalidAccountData)
+    );
+}
+
+#[tokio::test]
+async fn legacy_pools_only_allow_withdrawals() {
+    let Setup {
+        mut context,
+        program_id,
+        user,
+        user_token,
+        ..
+    } = setup().await;
+    let invalid_vault = TransactionError::InstructionError(
+        0,
+        InstructionError::Custom(StakingError::InvalidVault as u32),
+    );
+
+    // InitializePool only records a vault the pool authority controls
+    let pool = Keypair::new();
+    let rent = context.banks_client.get_rent().await.unwrap();
+    let create_account = system_instruction::create_account(
+        &context.payer.pubkey(),
+        &pool.pubkey(),
+        rent.minimum_balance(StakingPool::LEN),
+        StakingPool::LEN as u64,
+        &program_id,
+    );
+    let initialize_pool = Instruction::new_with_bytes(
+        program_id,
+        &[2],
+        vec![
+            AccountMeta::new(pool.pubkey(), false),
+            AccountMeta::new_readonly(context.payer.pubkey(), true),
+            AccountMeta::new_readonly(user_token, false),
+        ],
+    );
+    let (err, _) = try_send(&mut context, &[create_account, initialize_pool], &[&pool])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err,
+        TransactionError::InstructionError(
+            1,
+            InstructionError::Custom(StakingError::InvalidVault as u32)
+        )
+    );
+
+    // A pool initialized before vaults were recorded
+    let legacy_pool = Pubkey::new_unique();
+    let legacy_data = StakingPool {
+        discriminator: StakingPool::DISCRIMINATOR,
+        admin: context.payer.pubkey(),
+        is_initialized: true.into(),
+        ..StakingPool::default()
+    };
+    context.set_account(
+        &legacy_pool,
+        &Account {
+            lamports: rent.minimum_balance(StakingPool::LEN),
+            data: bytemuck::bytes_of(&legacy_data).to_vec(),
+            owner: program_id,
+            ..Account::default()
+        }
+        .into(),
+    );
+
+    let open_position = open_position(&program_id, &legacy_pool, &user.pubkey(), 0);
+    let (err, _) = try_send(&mut context, &[open_position], &[&user])
+        .await
+        .unwrap_err();
+    assert_eq!(err, invalid_vault);
+}
+
+#[tokio::test]
+async fn voter_weight_and_stakers_cover_every_position() {
+    let Setup {
+        mut context,
+        program_id,
+        mint,
+        user,
+        user_token,
+        pool,
+        user_positions,
+        position,
+        ..
+    } = setup().await;
+    let (second_position, _) = find_position_address(&program_id, &pool, &user.pubkey(), 1);
+    send(
+        &mut context,
+        &[
+            open_position(&program_id, &pool, &user.pubkey(), 1),
+            stake(
+                &program_id,
+                &pool,
+                &position,
+                &user.pubkey(),
+                &user_token,
+                STAKE_AMOUNT / 4,
+            ),
+            stake(
+                &program_id,
+                &pool,
+                &second_position,
+                &user.pubkey(),
+                &user_token,
+                STAKE_AMOUNT / 2,
+            ),
+        ],
+        &[&user],
+    )
+    .await;
+
+    let pool_account = context
+        .banks_client
+        .get_account(pool)
+        .await
+        .unwrap()
+        .unwrap();
+    let pool_data = StakingPool::load(&pool_account.data).unwrap();
+    assert_eq!(pool_data.stats.active_stakers, 1);
+    assert_eq!(pool_data.total_staked, STAKE_AMOUNT / 4 + STAKE_AMOUNT / 2);
+
+    // The voter weight record covers both positions
+    let realm = Pubkey::new_unique();
+    let mut set_realm_data = vec![35];
+    set_realm_data.extend_from_slice(realm.as_ref());
+    set_realm_data.extend_from_slice(mint.as_ref());
+    let set_realm = Instruction::new_with_bytes(
+        program_id,
+        &set_realm_data,
+        vec![
+            AccountMeta::new(pool, false),
+            AccountMeta::new_readonly(context.payer.pubkey(), true),
+        ],
+    );
+    let (record, _) = find_voter_weight_record(&program_id, &realm, &mint, &user.pubkey());
+    let mut update_data = vec![23];
+    update_data.extend_from_slice(realm.as_ref());
+    update_data.extend_from_slice(mint.as_ref());
+    let update = Instruction::new_with_bytes(
+        program_id,
+        &update_data,
+        vec![
+            AccountMeta::new_readonly(pool, false),
+            AccountMeta::new_readonly(user_positions, false),
+            AccountMeta::new(record, false),
+            AccountMeta::new(user.pubkey(), true),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    );
+    send(&mut context, &[set_realm, update], &[&user]).await;
+
+    let record_account = context
+        .banks_client
+        .get_account(record)
+        .await
+        .unwrap()
+        .unwrap();
+    let weight = u64::from_le_bytes(record_account.data[104..112].try_into().unwrap());
+    assert_eq!(weight, STAKE_AMOUNT / 4 + STAKE_AMOUNT / 2);
+}
+
+#[tokio::test]
+async fn stake_for_opens_the_beneficiary_position() {
+    let Setup {
+        mut context,
+        program_id,
+        user,
+        user_token,
+        pool,
+        vault,
+        ..
+    } = setup().await;
+
+    // The beneficiary never signs, the funder opens their first position and pays for it
+    let beneficiary = Pubkey::new_unique();
+    let (user_positions, _) = find_user_positions(&program_id, &pool, &beneficiary);
+    let (position, _) = find_position_address(&program_id, &pool, &beneficiary, 0);
+    let mut stake_for_data = vec![14];
+    stake_for_data.extend_from_slice(beneficiary.as_ref());
+    stake_for_data.extend_from_slice(&STAKE_AMOUNT.to_le_bytes());
+    let stake_for = Instruction::new_with_bytes(
+        program_id,
+        &stake_for_data,
+        vec![
+            AccountMeta::new(pool, false),
+            AccountMeta::new(position, false),
+            AccountMeta::new(user.pubkey(), true),
+            AccountMeta::new(user_token, false),
+            AccountMeta::new(vault, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new(user_positions, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    );
+    send(&mut context, &[stake_for], &[&user]).await;
+
+    let position_account = context
+        .banks_client
+        .get_account(position)
+        .await
+        .unwrap()
+        .unwrap();
+    let position_data = StakingAccount::load(&position_account.data).unwrap();
+    assert_eq!(position_data.user, beneficiary);
+    assert_eq!(position_data.amount_staked, STAKE_AMOUNT);
+    assert_eq!(
+        token_balance(&mut context.banks_client, vault).await,
+        REWARD_FUNDING + STAKE_AMOUNT
+    );
+}
@@ -12,13 +12,145 @@ import {
   // Define the staking instruction types
   const STAKE_INSTRUCTION = 0;
   const UNSTAKE_INSTRUCTION = 1;
+  const INITIALIZE_POOL_INSTRUCTION = 2;
+  const SET_PAUSED_INSTRUCTION = 3;
+  const EMERGENCY_WITHDRAW_INSTRUCTION = 4;
+  const GET_PENDING_REWARDS_INSTRUCTION = 26;
+  const SET_APR_INSTRUCTION = 32;
+  const CREATE_POOL_INSTRUCTION = 33;
+  const OPEN_POSITION_INSTRUCTION = 34;
+  const MAX_REWARD_STREAMS = 4;
+  
+  // Derive the pool, principal vault and pool authority addresses for (stakeMint, seed)
+  function findPoolAddresses(programId: PublicKey, stakeMint: PublicKey, seed: bigint) {
+    const seedBytes = Buffer.alloc(8);
+    seedBytes.writeBigUInt64LE(seed);
+    const [pool] = PublicKey.findProgramAddressSync(
+        [Buffer.from('pool'), stakeMint.toBuffer(), seedBytes],
+        programId
+    );
+    const [vault] = PublicKey.findProgramAddressSync([Buffer.from('vault'), pool.toBuffer()], programId);
+    const [authority] = PublicKey.findProgramAddressSync(
+        [Buffer.from('authority'), pool.toBuffer()],
+        programId
+    );
+    return { pool, vault, authority };
+  }
+  
+  // Function to create a new pool for a stake mint, returning its addresses
+  async function createPool(
+    connection: Connection,
+    admin: Keypair,
+    stakeMint: PublicKey,
+    seed: bigint,
+    programId: PublicKey
+  ) {
+    const { pool, vault, authority } = findPoolAddresses(programId, stakeMint, seed);
+    const instructionData = Buffer.alloc(9);
+    instructionData.writeUInt8(CREATE_POOL_INSTRUCTION, 0);
+    instructionData.writeBigUInt64LE(seed, 1);
+  
+    const transaction = new Transaction().add({
+        keys: [
+            { pubkey: pool, isSigner: false, isWritable: true },
+            { pubkey: admin.publicKey, isSigner: true, isWritable: true },
+            { pubkey: stakeMint, isSigner: false, isWritable: false },
+            { pubkey: vault, isSigner: false, isWritable: true },
+            { pubkey: authority, isSigner: false, isWritable: false },
+            { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+            { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        ],
+        programId,
+        data: instructionData,
+    });
+  
+    const signature = await sendAndConfirmTransaction(connection, transaction, [admin]);
+    console.log('Create pool transaction signature:', signature);
+    return { pool, vault, authority };
+  }
+  
+  // Derive the account counting a user's positions in a pool
+  function findUserPositionsAddress(programId: PublicKey, pool: PublicKey, owner: PublicKey) {
+    const [userPositions] = PublicKey.findProgramAddressSync(
+        [Buffer.from('positions'), pool.toBuffer(), owner.toBuffer()],
+        programId
+    );
+    return userPositions;
+  }
+  
+  // Derive the address of a user's position number `index` in a pool
+  function findPositionAddress(programId: PublicKey, pool: PublicKey, owner: PublicKey, index: bigint) {
+    const indexBytes = Buffer.alloc(8);
+    indexBytes.writeBigUInt64LE(index);
+    const [position] = PublicKey.findProgramAddressSync(
+        [Buffer.from('position'), pool.toBuffer(), owner.toBuffer(), indexBytes],
+        programId
+    );
+    return position;
+  }
+  
+  // List the addresses of every position a user has opened in a pool
+  async function listPositions(
+    connection: Connection,
+    pool: PublicKey,
+    owner: PublicKey,
+    programId: PublicKey
+  ) {
+    const counter = await connection.getAccountInfo(findUserPositionsAddress(programId, pool, owner));
+    if (!counter) {
+        return [];
+    }
+  
+    // Borsh-encoded UserPositions: owner, pool, then the position count
+    const positionCount = counter.data.readBigUInt64LE(64);
+    const positions: PublicKey[] = [];
+    for (let index = BigInt(0); index < positionCount; index++) {
+        positions.push(findPositionAddress(programId, pool, owner, index));
+    }
+    return positions;
+  }
+  
+  // Function to open the user's next position with its own lock duration
+  async function openPosition(
+    connection: Connection,
+    owner: Keypair,
+    pool: PublicKey,
+    lockDuration: bigint,
+    programId: PublicKey
+  ) {
+    const userPositions = findUserPositionsAddress(programId, pool, owner.publicKey);
+    const existing = await listPositions(connection, pool, owner.publicKey, programId);
+    const position = findPositionAddress(programId, pool, owner.publicKey, BigInt(existing.length));
+  
+    const instructionData = Buffer.alloc(9);
+    instructionData.writeUInt8(OPEN_POSITION_INSTRUCTION, 0);
+    instructionData.writeBigUInt64LE(lockDuration, 1);
+  
+    const transaction = new Transaction().add({
+        keys: [
+            { pubkey: pool, isSigner: false, isWritable: false },
+            { pubkey: userPositions, isSigner: false, isWritable: true },
+            { pubkey: position, isSigner: false, isWritable: true },
+            { pubkey: owner.publicKey, isSigner: true, isWritable: true },
+            { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        ],
+        programId,
+        data: instructionData,
+    });
+  
+    const signature = await sendAndConfirmTransaction(connection, transaction, [owner]);
+    console.log('Open position transaction signature:', signature);
+    return position;
+  }
   
   // Function to stake tokens
   async function stakeTokens(
     connection: Connection,
     payer: Keypair,
+    poolAccountPubkey: PublicKey,
     stakingAccountPubkey: PublicKey,
     userAccountPubkey: PublicKey,
+    userTokenAccountPubkey: PublicKey,
     tokenAccountPubkey: PublicKey,
     amount: number,
     programId: PublicKey
@@ -27,12 +159,19 @@ import {
     instructionData.writeUInt8(STAKE_INSTRUCTION, 0);
     instructionData.writeBigUInt64LE(BigInt(amount), 1);
   
+    // The user's running total in the pool, created with their first deposit
+    const userPositions = findUserPositionsAddress(programId, poolAccountPubkey, userAccountPubkey);
+  
     const transaction = new Transaction().add({
         keys: [
+            { pubkey: poolAccountPubkey, isSigner: false, isWritable: true },
             { pubkey: stakingAccountPubkey, isSigner: false, isWritable: true },
             { pubkey: userAccountPubkey, isSigner: true, isWritable: true },
+            { pubkey: userTokenAccountPubkey, isSigner: false, isWritable: true },
             { pubkey: tokenAccountPubkey, isSigner: false, isWritable: true },
             { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+            { pubkey: userPositions, isSigner: false, isWritable: true },
+            { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
   
         ],
         programId,
@@ -43,24 +182,106 @@ import {
     console.log('Stake transaction signature:', signature);
   }
   
+  // Function to read pending rewards computed by the program itself
+  async function getPendingRewards(
+    connection: Connection,
+    payer: Keypair,
+    poolAccountPubkey: PublicKey,
+    stakingAccountPubkey: PublicKey,
+    programId: PublicKey
+  ) {
+    const instructionData = Buffer.alloc(1);
+    instructionData.writeUInt8(GET_PENDING_REWARDS_INSTRUCTION, 0);
+  
+    const transaction = new Transaction().add({
+        keys: [
+            { pubkey: poolAccountPubkey, isSigner: false, isWritable: false },
+            { pubkey: stakingAccountPubkey, isSigner: false, isWritable: false },
+        ],
+        programId,
+        data: instructionData,
+    });
+    transaction.feePayer = payer.publicKey;
+  
+    const simulation = await connection.simulateTransaction(transaction, [payer]);
+    const returnData = simulation.value.returnData;
+    if (!returnData) {
+        throw new Error('GetPendingRewards returned no data');
+    }
+  
+    // Borsh-encoded PendingRewards
+    const data = Buffer.from(returnData.data[0], 'base64');
+    const streamRewards: bigint[] = [];
+    for (let i = 0; i < MAX_REWARD_STREAMS; i++) {
+        streamRewards.push(data.readBigUInt64LE(25 + i * 8));
+    }
+    const aprBps = data.readBigUInt64LE(25 + MAX_REWARD_STREAMS * 8);
+    return {
+        timestamp: data.readBigUInt64LE(0),
+        amountStaked: data.readBigUInt64LE(8),
+        rewards: data.readBigUInt64LE(16),
+        minDurationReached: data.readUInt8(24) === 1,
+        streamRewards,
+        aprBps,
+        apr: formatApr(aprBps),
+    };
+  }
+  
+  // Render an APR in basis points as a percentage, e.g. 1250 -> "12.50%"
+  function formatApr(aprBps: bigint): string {
+    const hundred = BigInt(100);
+    return `${aprBps / hundred}.${(aprBps % hundred).toString().padStart(2, '0')}%`;
+  }
+  
+  // Function to configure a pool's annual percentage rate, in basis points
+  async function setApr(
+    connection: Connection,
+    admin: Keypair,
+    poolAccountPubkey: PublicKey,
+    aprBps: number,
+    programId: PublicKey
+  ) {
+    const instructionData = Buffer.alloc(5);
+    instructionData.writeUInt8(SET_APR_INSTRUCTION, 0);
+    instructionData.writeUInt32LE(aprBps, 1);
+  
+    const transaction = new Transaction().add({
+        keys: [
+            { pubkey: poolAccountPubkey, isSigner: false, isWritable: true },
+            { pubkey: admin.publicKey, isSigner: true, isWritable: false },
+        ],
+        programId,
+        data: instructionData,
+    });
+  
+    const signature = await sendAndConfirmTransaction(connection, transaction, [admin]);
+    console.log(`Set APR to ${formatApr(BigInt(aprBps))}:`, signature);
+  }
+  
   // Function to unstake tokens
   // async function unstakeTokens(
   //   connection: Connection,
   //   payer: Keypair,
+  //   poolAccountPubkey: PublicKey,
   //   stakingAccountPubkey: PublicKey,
   //   userAccountPubkey: PublicKey,
+  //   userTokenAccountPubkey: PublicKey,
   //   tokenAccountPubkey: PublicKey,
   //   programId: PublicKey
   // ) {
   //   const instructionData = Buffer.alloc(1);
   //   instructionData.writeUInt8(UNSTAKE_INSTRUCTION, 0);
+  //   const userPositions = findUserPositionsAddress(programId, poolAccountPubkey, userAccountPubkey);
   
   //   const transaction = new Transaction().add({
   //       keys: [
+  //           { pubkey: poolAccountPubkey, isSigner: false, isWritable: true },
   //           { pubkey: stakingAccountPubkey, isSigner: false, isWritable: true },
   //           { pubkey: userAccountPubkey, isSigner: true, isWritable: true },
+  //           { pubkey: userTokenAccountPubkey, isSigner: false, isWritable: true },
   //           { pubkey: tokenAccountPubkey, isSigner: false, isWritable: true },
               //  { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+  //           { pubkey: userPositions, isSigner: false, isWritable: true },
   
   //       ],
   //       programId,
@@ -77,15 +298,21 @@ import {
     const payer = Keypair.fromSecretKey(new Uint8Array([110,148,101,43,68,224,191,58,214,149,24,35,232,92,178,182,189,215,60,186,160,72,160,161,228,23,169,47,101,30,51,164,161,69,10,78,143,157,61,30,171,68,95,19,184,207,213,128,5,202,23,24,224,109,11,165,33,217,167,126,177,82,76,23]
   
     )); // Replace with your payer's Keypair
+    const poolAccountPubkey = new PublicKey('8FbgJjkWobrVBgTFM1aoMhCYp6TZ21ymuNvZHdJctRRd'); // Replace with your pool account public key
     const stakingAccountPubkey = new PublicKey('8FbgJjkWobrVBgTFM1aoMhCYp6TZ21ymuNvZHdJctRRd'); // Replace with your staking account public key
     const userAccountPubkey = new PublicKey('BrXhY67ZAei7KsdYF6EDucLAK98ibMLvcNDitGS392Mk'); // Replace with your user account public key
+    const userTokenAccountPubkey = new PublicKey('BrXhY67ZAei7KsdYF6EDucLAK98ibMLvcNDitGS392Mk'); // Replace with your user's token account public key
     const tokenAccountPubkey = new PublicKey('8b2rE9hcUJjEaMGN8XEoS9vEbGQPc38VKR1bk34x1xxw'); // Replace with your token account public key
     const programId = new PublicKey('6PoJTdCNMpKKQU6xPRB2fubDJHE42zyJDRfbp6W2cJBQ'); // Replace with your program's public key
   
     // Stake tokens
-    await stakeTokens(connection, payer, stakingAccountPubkey, userAccountPubkey, tokenAccountPubkey, 100, programId);
+    await stakeTokens(connection, payer, poolAccountPubkey, stakingAccountPubkey, userAccountPubkey, userTokenAccountPubkey, tokenAccountPubkey, 100, programId);
+  
+    // Show the position's pending rewards and current APR
+    const pending = await getPendingRewards(connection, payer, poolAccountPubkey, stakingAccountPubkey, programId);
+    console.log(`Pending rewards: ${pending.rewards} at ${pending.apr} APR`);
   
     // Unstake tokens
-    // await unstakeTokens(connection, payer, stakingAccountPubkey, userAccountPubkey, tokenAccountPubkey, programId);
+    // await unstakeTokens(connection, payer, poolAccountPubkey, stakingAccountPubkey, userAccountPubkey, userTokenAccountPubkey, tokenAccountPubkey, programId);
   })();
   
\ No newline at end of file